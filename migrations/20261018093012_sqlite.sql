@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS "telegram_location" (
+    "chat_id"    INTEGER NOT NULL,
+    "msg_id"     INTEGER NOT NULL,
+    "media_json" TEXT    NOT NULL,
+
+    UNIQUE("chat_id", "msg_id") ON CONFLICT REPLACE
+);
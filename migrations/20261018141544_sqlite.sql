@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS "user_settings" (
+    "tg_user_id"    INTEGER NOT NULL UNIQUE,
+    "settings_json" TEXT    NOT NULL
+);
@@ -0,0 +1 @@
+ALTER TABLE "telegram_media_group" ADD COLUMN "received_at" INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+ALTER TABLE "telegram_media_group" ADD COLUMN "chat_id" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "telegram_location" ADD COLUMN "received_at" INTEGER NOT NULL DEFAULT 0;
+
+CREATE INDEX IF NOT EXISTS "telegram_media_group_chat_id" ON "telegram_media_group" ("chat_id");
+CREATE INDEX IF NOT EXISTS "telegram_media_group_received_at" ON "telegram_media_group" ("received_at");
+CREATE INDEX IF NOT EXISTS "telegram_location_received_at" ON "telegram_location" ("received_at");
+
+CREATE TABLE IF NOT EXISTS "cache_chat" (
+    "chat_id"      INTEGER NOT NULL UNIQUE,
+    "enabled"      INTEGER,
+    "last_used_at" INTEGER
+);
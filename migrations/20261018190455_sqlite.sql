@@ -0,0 +1,24 @@
+ALTER TABLE "cache_chat" ADD COLUMN "messages" INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS "telegram_message" (
+    "chat_id"         INTEGER NOT NULL,
+    "msg_id"          INTEGER NOT NULL,
+    "sender_id"       INTEGER,
+    "reply_to_msg_id" INTEGER,
+    "msg_json"        TEXT    NOT NULL,
+    "received_at"     INTEGER NOT NULL,
+
+    UNIQUE("chat_id", "msg_id")
+);
+
+CREATE INDEX IF NOT EXISTS "telegram_message_received_at" ON "telegram_message" ("received_at");
+
+-- Previous versions of edited messages
+CREATE TABLE IF NOT EXISTS "telegram_message_edit" (
+    "chat_id"     INTEGER NOT NULL,
+    "msg_id"      INTEGER NOT NULL,
+    "msg_json"    TEXT    NOT NULL,
+    "replaced_at" INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS "telegram_message_edit_msg" ON "telegram_message_edit" ("chat_id", "msg_id");
@@ -0,0 +1,13 @@
+-- Fediverse handles of Telegram users, registered by the users themselves
+-- ("chat_id" 0) or by administrators of a chat for that chat
+CREATE TABLE IF NOT EXISTS "fedi_handle" (
+    "chat_id"     INTEGER NOT NULL,
+    "tg_user_id"  INTEGER,
+    "tg_username" TEXT,
+    "handle"      TEXT    NOT NULL,
+
+    UNIQUE("chat_id", "tg_user_id"),
+    UNIQUE("chat_id", "tg_username")
+);
+
+CREATE INDEX IF NOT EXISTS "fedi_handle_tg_username" ON "fedi_handle" ("tg_username");
@@ -0,0 +1,16 @@
+-- Statuses synced from Telegram messages, to rewrite links to the messages in
+-- later statuses of the same account. A forwarded channel post is also
+-- recorded under the original post.
+CREATE TABLE IF NOT EXISTS "sync_record" (
+    "tg_user_id"    INTEGER NOT NULL,
+    "domain"        TEXT    NOT NULL,
+    "chat_id"       INTEGER NOT NULL,
+    "chat_username" TEXT,
+    "msg_id"        INTEGER NOT NULL,
+    "status_url"    TEXT    NOT NULL,
+    "synced_at"     INTEGER NOT NULL,
+
+    UNIQUE("tg_user_id", "domain", "chat_id", "msg_id") ON CONFLICT REPLACE
+);
+
+CREATE INDEX IF NOT EXISTS "sync_record_chat_username" ON "sync_record" ("chat_username");
@@ -0,0 +1 @@
+ALTER TABLE "sync_record" ADD COLUMN "status_id" TEXT;
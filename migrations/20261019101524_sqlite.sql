@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS "telegram_message_edit_replaced_at" ON "telegram_message_edit" ("replaced_at");
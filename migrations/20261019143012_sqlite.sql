@@ -0,0 +1,8 @@
+-- Handles registered by the users themselves have to be verified before
+-- mentions are rewritten to them, by the linked Mastodon account or a code in
+-- the profile. Mappings of administrators are trusted as they are.
+ALTER TABLE "fedi_handle" ADD COLUMN "verified"    INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "fedi_handle" ADD COLUMN "verify_code" TEXT;
+
+UPDATE "fedi_handle" SET "verified" = 1 WHERE "chat_id" != 0;
+UPDATE "fedi_handle" SET "verify_code" = 'tgms-' || lower(hex(randomblob(4))) WHERE "chat_id" = 0;
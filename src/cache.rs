@@ -0,0 +1,507 @@
+use std::{env, fmt, str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::bail;
+use once_cell::sync::Lazy;
+use spdlog::prelude::*;
+use teloxide::types::{Chat, ChatId};
+use tokio::time;
+
+use crate::{
+    config,
+    util::{env_duration_or, env_or, unix_millis},
+    InstanceState,
+};
+
+// Which chats albums and locations are cached for, they must be cached in
+// advance to be synced with `/post`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheScope {
+    // All chats the bot is in
+    All,
+    // Chats where `/post` has been used
+    Used,
+    // Only chats that opted in with `/cache +enable`
+    OptIn,
+}
+
+impl FromStr for CacheScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Self::All),
+            "used" => Ok(Self::Used),
+            "opt_in" => Ok(Self::OptIn),
+            _ => bail!("invalid cache scope '{s}', expected one of all/used/opt_in"),
+        }
+    }
+}
+
+impl fmt::Display for CacheScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => write!(f, "all"),
+            Self::Used => write!(f, "used"),
+            Self::OptIn => write!(f, "opt_in"),
+        }
+    }
+}
+
+pub static SCOPE: Lazy<CacheScope> = Lazy::new(|| {
+    let Ok(value) = env::var(config::CACHE_SCOPE_ENV_VAR) else {
+        return CacheScope::All;
+    };
+
+    value.parse().unwrap_or_else(|err| {
+        warn!("{err}, fallback to 'all'");
+        CacheScope::All
+    })
+});
+
+pub static MAX_AGE: Lazy<Duration> =
+    Lazy::new(|| env_duration_or(config::CACHE_MAX_AGE_ENV_VAR, config::DEFAULT_CACHE_MAX_AGE));
+
+// Per table
+pub static MAX_ROWS: Lazy<usize> = Lazy::new(|| {
+    env_or(
+        config::CACHE_MAX_ROWS_ENV_VAR,
+        config::DEFAULT_CACHE_MAX_ROWS,
+    )
+});
+
+pub struct ChatCache {
+    // Explicitly enabled or disabled with `/cache`, `None` follows the scope
+    pub enabled: Option<bool>,
+    pub used: bool,
+    // Whether full messages are cached as well, see `util::msgcache`
+    pub messages: bool,
+}
+
+impl ChatCache {
+    pub async fn load(inst_state: &InstanceState, chat_id: ChatId) -> anyhow::Result<Self> {
+        let chat_id = chat_id.0;
+
+        let record = sqlx::query!(
+            r#"
+SELECT enabled, last_used_at, messages
+FROM cache_chat
+WHERE chat_id = ?1
+        "#,
+            chat_id,
+        )
+        .fetch_optional(inst_state.db.pool())
+        .await?;
+
+        Ok(match record {
+            Some(record) => Self {
+                enabled: record.enabled.map(|enabled| enabled != 0),
+                used: record.last_used_at.is_some(),
+                messages: record.messages != 0,
+            },
+            None => Self {
+                enabled: None,
+                used: false,
+                messages: false,
+            },
+        })
+    }
+
+    pub fn is_enabled(&self, chat: &Chat) -> bool {
+        self.enabled
+            .unwrap_or_else(|| Self::default_enabled(chat, self.used))
+    }
+
+    // Private chats are always cached by default, the bot is used there anyway
+    pub fn default_enabled(chat: &Chat, used: bool) -> bool {
+        match *SCOPE {
+            CacheScope::All => true,
+            _ if chat.is_private() => true,
+            CacheScope::Used => used,
+            CacheScope::OptIn => false,
+        }
+    }
+}
+
+pub async fn should_cache(inst_state: &InstanceState, chat: &Chat) -> anyhow::Result<bool> {
+    if *SCOPE == CacheScope::All {
+        return Ok(true);
+    }
+    Ok(ChatCache::load(inst_state, chat.id).await?.is_enabled(chat))
+}
+
+pub async fn mark_used(inst_state: &InstanceState, chat_id: ChatId) -> anyhow::Result<()> {
+    let (chat_id, now) = (chat_id.0, unix_millis());
+
+    sqlx::query!(
+        r#"
+INSERT INTO cache_chat ( chat_id, last_used_at )
+VALUES ( ?1, ?2 )
+ON CONFLICT ( chat_id ) DO UPDATE SET last_used_at = excluded.last_used_at
+        "#,
+        chat_id,
+        now
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_enabled(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    enabled: bool,
+) -> anyhow::Result<()> {
+    let chat_id = chat_id.0;
+
+    sqlx::query!(
+        r#"
+INSERT INTO cache_chat ( chat_id, enabled )
+VALUES ( ?1, ?2 )
+ON CONFLICT ( chat_id ) DO UPDATE SET enabled = excluded.enabled
+        "#,
+        chat_id,
+        enabled
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(())
+}
+
+pub async fn set_messages(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    enabled: bool,
+) -> anyhow::Result<()> {
+    let chat_id = chat_id.0;
+
+    sqlx::query!(
+        r#"
+INSERT INTO cache_chat ( chat_id, messages )
+VALUES ( ?1, ?2 )
+ON CONFLICT ( chat_id ) DO UPDATE SET messages = excluded.messages
+        "#,
+        chat_id,
+        enabled
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(())
+}
+
+// Returns the number of deleted rows
+pub async fn purge_chat(inst_state: &InstanceState, chat_id: ChatId) -> anyhow::Result<u64> {
+    let chat_id = chat_id.0;
+    let mut tx = inst_state.db.pool().begin().await?;
+
+    let media_group = sqlx::query!(
+        r#"
+DELETE FROM telegram_media_group
+WHERE chat_id = ?1
+        "#,
+        chat_id,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let location = sqlx::query!(
+        r#"
+DELETE FROM telegram_location
+WHERE chat_id = ?1
+        "#,
+        chat_id,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    let message = sqlx::query!(
+        r#"
+DELETE FROM telegram_message
+WHERE chat_id = ?1
+        "#,
+        chat_id,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+DELETE FROM telegram_message_edit
+WHERE chat_id = ?1
+        "#,
+        chat_id,
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(media_group.rows_affected() + location.rows_affected() + message.rows_affected())
+}
+
+// Forgets everything about the chat, when the bot left it
+pub async fn forget_chat(inst_state: &InstanceState, chat_id: ChatId) -> anyhow::Result<u64> {
+    let deleted = purge_chat(inst_state, chat_id).await?;
+    let chat_id = chat_id.0;
+
+    sqlx::query!(
+        r#"
+DELETE FROM cache_chat
+WHERE chat_id = ?1
+        "#,
+        chat_id,
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(deleted)
+}
+
+// Deletes rows older than `MAX_AGE`, then the oldest rows exceeding `MAX_ROWS`.
+// Rows cached before the arrival time was recorded are treated as the oldest.
+// Previous versions of edited messages count by the time they were replaced.
+// Returns the number of deleted rows.
+pub async fn prune(inst_state: &InstanceState) -> anyhow::Result<u64> {
+    let expired_before = unix_millis() - MAX_AGE.as_millis() as i64;
+    let max_rows = *MAX_ROWS as i64;
+    let mut tx = inst_state.db.pool().begin().await?;
+
+    let mut deleted = sqlx::query!(
+        r#"
+DELETE FROM telegram_media_group
+WHERE received_at < ?1
+        "#,
+        expired_before,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_location
+WHERE received_at < ?1
+        "#,
+        expired_before,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_message
+WHERE received_at < ?1
+        "#,
+        expired_before,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_message_edit
+WHERE replaced_at < ?1
+        "#,
+        expired_before,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_media_group
+WHERE rowid IN (
+    SELECT rowid
+    FROM telegram_media_group
+    ORDER BY received_at DESC, msg_id DESC
+    LIMIT -1 OFFSET ?1
+)
+        "#,
+        max_rows,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_location
+WHERE rowid IN (
+    SELECT rowid
+    FROM telegram_location
+    ORDER BY received_at DESC, msg_id DESC
+    LIMIT -1 OFFSET ?1
+)
+        "#,
+        max_rows,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_message
+WHERE rowid IN (
+    SELECT rowid
+    FROM telegram_message
+    ORDER BY received_at DESC, msg_id DESC
+    LIMIT -1 OFFSET ?1
+)
+        "#,
+        max_rows,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_message_edit
+WHERE rowid IN (
+    SELECT rowid
+    FROM telegram_message_edit
+    ORDER BY replaced_at DESC, rowid DESC
+    LIMIT -1 OFFSET ?1
+)
+        "#,
+        max_rows,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    // Edit history goes with the message
+    deleted += sqlx::query!(
+        r#"
+DELETE FROM telegram_message_edit
+WHERE NOT EXISTS (
+    SELECT 1
+    FROM telegram_message
+    WHERE telegram_message.chat_id = telegram_message_edit.chat_id
+        AND telegram_message.msg_id = telegram_message_edit.msg_id
+)
+        "#,
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    tx.commit().await?;
+
+    Ok(deleted)
+}
+
+pub fn spawn_pruning(inst_state: Arc<InstanceState>) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(config::CACHE_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            match prune(&inst_state).await {
+                Ok(0) => {}
+                Ok(deleted) => info!("pruned {deleted} cached message(s)"),
+                Err(err) => error!("failed to prune cache: {err}"),
+            }
+        }
+    });
+}
+
+pub struct CacheStats {
+    pub media_group_rows: i64,
+    pub media_groups: i64,
+    pub location_rows: i64,
+    pub message_rows: i64,
+    pub message_edit_rows: i64,
+    pub chats: i64,
+    pub bytes: i64,
+    pub oldest_received_at: Option<i64>,
+}
+
+pub async fn stats(inst_state: &InstanceState) -> anyhow::Result<CacheStats> {
+    let media_group = sqlx::query!(
+        r#"
+SELECT
+    COUNT(*) AS "rows!: i64",
+    COUNT(DISTINCT group_id) AS "groups!: i64",
+    COALESCE(SUM(LENGTH(media_json)), 0) AS "bytes!: i64",
+    MIN(received_at) AS "oldest_received_at: i64"
+FROM telegram_media_group
+        "#,
+    )
+    .fetch_one(inst_state.db.pool())
+    .await?;
+
+    let location = sqlx::query!(
+        r#"
+SELECT
+    COUNT(*) AS "rows!: i64",
+    COALESCE(SUM(LENGTH(media_json)), 0) AS "bytes!: i64",
+    MIN(received_at) AS "oldest_received_at: i64"
+FROM telegram_location
+        "#,
+    )
+    .fetch_one(inst_state.db.pool())
+    .await?;
+
+    let message = sqlx::query!(
+        r#"
+SELECT
+    COUNT(*) AS "rows!: i64",
+    COALESCE(SUM(LENGTH(msg_json)), 0) AS "bytes!: i64",
+    MIN(received_at) AS "oldest_received_at: i64"
+FROM telegram_message
+        "#,
+    )
+    .fetch_one(inst_state.db.pool())
+    .await?;
+
+    let message_edit = sqlx::query!(
+        r#"
+SELECT
+    COUNT(*) AS "rows!: i64",
+    COALESCE(SUM(LENGTH(msg_json)), 0) AS "bytes!: i64"
+FROM telegram_message_edit
+        "#,
+    )
+    .fetch_one(inst_state.db.pool())
+    .await?;
+
+    let chats = sqlx::query!(
+        r#"
+SELECT COUNT(*) AS "chats!: i64"
+FROM (
+    SELECT chat_id FROM telegram_media_group
+    UNION
+    SELECT chat_id FROM telegram_location
+    UNION
+    SELECT chat_id FROM telegram_message
+)
+        "#,
+    )
+    .fetch_one(inst_state.db.pool())
+    .await?;
+
+    Ok(CacheStats {
+        media_group_rows: media_group.rows,
+        media_groups: media_group.groups,
+        location_rows: location.rows,
+        message_rows: message.rows,
+        message_edit_rows: message_edit.rows,
+        chats: chats.chats,
+        bytes: media_group.bytes + location.bytes + message.bytes + message_edit.bytes,
+        oldest_received_at: [
+            media_group.oldest_received_at,
+            location.oldest_received_at,
+            message.oldest_received_at,
+        ]
+        .into_iter()
+        .flatten()
+        .min(),
+    })
+}
@@ -1,6 +1,6 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 
 pub(crate) trait Args: Default {
     fn help() -> &'static str;
@@ -87,6 +87,31 @@ pub(crate) enum ArgValue {
     KV(String), // `arg=abc`
 }
 
+// `30m`, `12h`, `1d`, `1w`, or plain seconds
+pub(crate) fn parse_duration(input: impl AsRef<str>) -> anyhow::Result<Duration> {
+    let input = input.as_ref();
+
+    let (number, unit) = input.split_at(
+        input
+            .find(|ch: char| !ch.is_ascii_digit())
+            .unwrap_or(input.len()),
+    );
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| anyhow!("invalid duration '{input}'"))?;
+
+    let secs = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => bail!("invalid duration unit in '{input}', expected one of s/m/h/d/w"),
+    };
+
+    Ok(Duration::from_secs(number * secs))
+}
+
 #[macro_export]
 macro_rules! define_cmd_args {
     ( $help:literal $(#[$attrs:meta])* $vis:vis struct $name:ident { $($body:tt)* } ) => {
@@ -125,7 +150,7 @@ macro_rules! define_cmd_args {
     };
     ( @ARM, $input:expr, $result:expr,
       $(#[$attrs:meta])* $vis:vis $name:ident : Option<String>, $($body:tt)*) => {
-        if let (stringify!($name), Some(ArgValue::KV(value))) = $input {
+        if let (stringify!($name), Some($crate::cmd::ArgValue::KV(value))) = $input {
             $result.$name = Some(value.into());
             return true;
         } else {
@@ -208,4 +233,18 @@ mod tests {
         assert!(TestArgs::parse("+opt_string").is_err());
         assert!(TestArgs::parse("-opt_string").is_err());
     }
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_duration("12h").unwrap(), Duration::from_secs(43200));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        assert_eq!(parse_duration("2w").unwrap(), Duration::from_secs(1209600));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("1y").is_err());
+        assert!(parse_duration("1.5h").is_err());
+    }
 }
@@ -21,6 +21,34 @@ pub enum Command {
         description = "post the message you replied to mastodon (send with `help` for advanced usages)"
     )]
     Post(String),
+    #[command(
+        description = "view or change your default options of /post (send with `help` for details)"
+    )]
+    Settings(String),
+    #[command(
+        description = "view or change the layout of synced statuses (send with `help` for details)"
+    )]
+    Template(String),
+    #[command(
+        description = "view or change your hashtag rules of /post (send with `help` for details)"
+    )]
+    Hashtags(String),
+    #[command(
+        rename = "fedi_handle",
+        description = "map telegram mentions to fediverse handles (send with `help` for details)"
+    )]
+    FediHandle(String),
+    #[command(
+        rename = "rules_text",
+        description = "view or change your text replacement rules of /post (send with `help` for details)"
+    )]
+    RulesText(String),
+    #[command(
+        description = "view or change caching of albums and locations in this chat (send with `help` for details)"
+    )]
+    Cache(String),
     #[command(description = "off")]
     Broadcast(String),
+    #[command(description = "off")]
+    CacheStats,
 }
@@ -3,7 +3,23 @@ use tokio::time::Duration;
 
 pub const BOT_TOKEN_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_BOT_TOKEN";
 pub const DB_URL_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_DATABASE_URL";
+pub const BOT_API_URL_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_BOT_API_URL";
 pub const ADMIN_TG_USER_ID_ENV_VAR: &str = "TGBOT_MASTODON_ADMIN_TG_USER_ID";
+pub const MAP_URL_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_MAP_URL";
+pub const FFMPEG_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_FFMPEG";
+pub const IMAGE_QUALITY_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_IMAGE_QUALITY";
+pub const MEDIA_PROCESSING_INTERVAL_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_MEDIA_PROCESSING_INTERVAL";
+pub const MEDIA_PROCESSING_TIMEOUT_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_MEDIA_PROCESSING_TIMEOUT";
+pub const CACHE_SCOPE_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_CACHE_SCOPE";
+pub const CACHE_MAX_AGE_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_CACHE_MAX_AGE";
+pub const CACHE_MAX_ROWS_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_CACHE_MAX_ROWS";
+pub const MAX_CONCURRENT_UPLOADS_ENV_VAR: &str = "TGBOT_MASTODON_SYNC_MAX_CONCURRENT_UPLOADS";
+pub const MAX_CONCURRENT_UPLOADS_PER_INSTANCE_ENV_VAR: &str =
+    "TGBOT_MASTODON_SYNC_MAX_CONCURRENT_UPLOADS_PER_INSTANCE";
+
+// `{lat}` and `{lon}` will be replaced with the coordinates
+pub const DEFAULT_MAP_URL: &str =
+    "https://www.openstreetmap.org/?mlat={lat}&mlon={lon}#map=17/{lat}/{lon}";
 
 // We can't support all languages, because that would make the detection very
 // slow.
@@ -11,9 +27,59 @@ pub const ADMIN_TG_USER_ID_ENV_VAR: &str = "TGBOT_MASTODON_ADMIN_TG_USER_ID";
 // If you want your language to be supported, please open an issue or PR.
 pub const DETECT_LANGUAGES: &[Language] = &[Chinese, English, Japanese, Korean, Russian, Ukrainian];
 
-// TODO: make this configurable from CLI
 pub const WAITING_FOR_SERVER_PROCESS_MEDIA_INTERVAL: Duration = Duration::from_secs(1);
 pub const WAITING_FOR_SERVER_PROCESS_MEDIA_TIMEOUT: Duration = Duration::from_secs(30);
+// Upper bound of waiting in the background, to not poll media that will never
+// finish forever
+pub const WAITING_FOR_SERVER_PROCESS_MEDIA_BACKGROUND_TIMEOUT: Duration =
+    Duration::from_secs(60 * 60);
+
+// JPEG quality (1-100) used when an image has to be recompressed to fit the
+// instance limits
+pub const DEFAULT_IMAGE_QUALITY: u8 = 90;
+
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 8;
+pub const DEFAULT_MAX_CONCURRENT_UPLOADS_PER_INSTANCE: usize = 4;
+
+pub const STREAMING_BUFFER_SIZE: usize = 64 * 1024;
+pub const PROGRESS_UPDATE_INTERVAL: Duration = Duration::from_secs(2);
+
+// Album items arrive as separate updates. An album is considered complete if no
+// new items arrived within the settle period, or it reached the maximum size.
+pub const MEDIA_GROUP_SETTLE_PERIOD: Duration = Duration::from_secs(2);
+pub const MEDIA_GROUP_MAX_SETTLE_WAIT: Duration = Duration::from_secs(15);
+pub const MEDIA_GROUP_MAX_SIZE: usize = 10;
+
+pub const DEFAULT_CACHE_MAX_AGE: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+pub const DEFAULT_CACHE_MAX_ROWS: usize = 100_000;
+pub const CACHE_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Previous versions kept per cached message, e.g. live locations are edited
+// every few seconds. The oldest ones are dropped first.
+pub const CACHE_MAX_EDITS_PER_MESSAGE: usize = 10;
+
+// Statuses synced as a thread with `/post count=` or `until=` at most
+pub const MAX_THREAD_LEN: usize = 20;
+
+pub const DEFAULT_POLL_EXPIRES: Duration = Duration::from_secs(60 * 60 * 24);
+// Polls without a close time are checked this often for being stopped by hand,
+// each check forwards the poll unless the chat caches messages
+pub const POLL_RESULTS_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const POLL_RESULTS_MAX_WAIT: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+// Excerpt of the message replied to with `/post +quote`, shortened further to
+// fit the character limit of the instance, or omitted below the minimum
+pub const QUOTE_MAX_CHARS: usize = 200;
+pub const QUOTE_MIN_CHARS: usize = 20;
+
+// Per user, see `/rules_text`
+pub const MAX_TEXT_RULES: usize = 50;
+pub const TEXT_RULE_REGEX_SIZE_LIMIT: usize = 1024 * 1024;
+
+// Delay between statuses posted by `import`, to not hit the rate limit of the
+// instance and flood the timelines of followers
+pub const DEFAULT_IMPORT_INTERVAL: Duration = Duration::from_secs(30);
+// Saved in the export directory by default
+pub const IMPORT_CHECKPOINT_FILE_NAME: &str = "tgbot-mastodon-sync-import.json";
 
 pub struct Package {
     pub name: &'static str,
@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use spdlog::prelude::*;
+use teloxide::requests::Requester;
+
+use crate::{
+    cache::{self, ChatCache},
+    cmd::{define_cmd_args, Args},
+    handler::{Request, Response},
+    util::{format_size, text::*, unix_millis},
+};
+
+pub async fn handle<'a>(
+    req: &Request,
+    arg: impl Into<String>,
+) -> Result<Response<'a>, Response<'a>> {
+    let args = CacheArgs::parse(arg.into())
+        .map_err(|err| Response::reply_to(format!("Failed to parse arguments.\n\n{err}")))?;
+    if args.help {
+        return Ok(Response::reply_to(mtb().pre(CacheArgs::help()).build()));
+    }
+
+    let chat = &req.msg().chat;
+    let user = req
+        .msg()
+        .from()
+        .ok_or_else(|| Response::reply_to("No user."))?;
+
+    let changing = args.enable.is_some() || args.messages.is_some() || args.purge;
+    if changing && !chat.is_private() {
+        let member = req
+            .bot()
+            .get_chat_member(chat.id, user.id)
+            .await
+            .map_err(|err| Response::reply_to(format!("Failed to query chat member.\n\n{err}")))?;
+        if !member.kind.is_privileged() {
+            return Err(Response::reply_to(
+                "Only administrators of the chat can change caching.",
+            ));
+        }
+    }
+
+    if let Some(enable) = args.enable {
+        cache::set_enabled(req.state(), chat.id, enable)
+            .await
+            .map_err(|err| Response::reply_to(format!("Failed to save settings.\n\n{err}")))?;
+        info!(
+            "user '{}' {} caching for chat '{}'",
+            user.id,
+            if enable { "enabled" } else { "disabled" },
+            chat.id
+        );
+    }
+
+    // Messages are only cached for enabled chats, so disabling turns them off
+    // too, instead of caching them again right after the purge
+    let messages = match args.enable {
+        Some(false) => Some(false),
+        _ => args.messages,
+    };
+    if let Some(messages) = messages {
+        cache::set_messages(req.state(), chat.id, messages)
+            .await
+            .map_err(|err| Response::reply_to(format!("Failed to save settings.\n\n{err}")))?;
+        info!(
+            "user '{}' {} caching messages for chat '{}'",
+            user.id,
+            if messages { "enabled" } else { "disabled" },
+            chat.id
+        );
+    }
+
+    let mut purged = None;
+    if args.purge || args.enable == Some(false) {
+        let deleted = cache::purge_chat(req.state(), chat.id)
+            .await
+            .map_err(|err| {
+                error!("failed to purge cache of chat '{}': {err}", chat.id);
+                Response::reply_to(format!("Failed to purge cache.\n\n{err}"))
+            })?;
+        purged = Some(deleted);
+    }
+
+    let chat_cache = ChatCache::load(req.state(), chat.id)
+        .await
+        .map_err(|err| Response::reply_to(format!("Failed to load settings.\n\n{err}")))?;
+    let on_off = |enable| if enable { "on" } else { "off" };
+
+    let mut resp = mtb()
+        .bold("Caching of this chat\n\n")
+        .plain(format!(
+            "enable: {} ({})\n",
+            on_off(chat_cache.is_enabled(chat)),
+            match chat_cache.enabled {
+                Some(_) => "set by /cache",
+                None => "default of the server",
+            }
+        ))
+        .plain(format!(
+            "messages: {}\n",
+            on_off(chat_cache.is_enabled(chat) && chat_cache.messages)
+        ));
+    if let Some(deleted) = purged {
+        resp = resp.plain(format!("\nPurged {deleted} cached message(s).\n"));
+    }
+
+    Ok(Response::reply_to(
+        resp.plain("\nSend ")
+            .code("/cache help")
+            .plain(" for how to change it.")
+            .build(),
+    ))
+}
+
+pub async fn stats<'a>(req: &Request) -> Result<Response<'a>, Response<'a>> {
+    let stats = cache::stats(req.state()).await.map_err(|err| {
+        error!("failed to query cache stats: {err}");
+        Response::reply_to(format!("Failed to query cache stats.\n\n{err}"))
+    })?;
+
+    let oldest = match stats.oldest_received_at {
+        None => "-".into(),
+        Some(0) => "unknown (cached by an older version)".into(),
+        Some(received_at) => format!(
+            "{} ago",
+            format_age(Duration::from_millis(
+                (unix_millis() - received_at).max(0) as u64
+            ))
+        ),
+    };
+
+    Ok(Response::reply_to(
+        mtb()
+            .bold("Cache stats\n\n")
+            .plain(format!(
+                "albums: {} ({} message(s))\n",
+                stats.media_groups, stats.media_group_rows
+            ))
+            .plain(format!("locations: {}\n", stats.location_rows))
+            .plain(format!(
+                "messages: {} ({} edit(s))\n",
+                stats.message_rows, stats.message_edit_rows
+            ))
+            .plain(format!("chats: {}\n", stats.chats))
+            .plain(format!("size: {}\n", format_size(stats.bytes as u64)))
+            .plain(format!("oldest: {oldest}\n\n"))
+            .plain(format!(
+                "scope: {}\nmax age: {}\nmax rows: {}",
+                *cache::SCOPE,
+                format_age(*cache::MAX_AGE),
+                *cache::MAX_ROWS
+            ))
+            .build(),
+    ))
+}
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        _ if secs >= 60 * 60 * 24 => format!("{}d", secs / (60 * 60 * 24)),
+        _ if secs >= 60 * 60 => format!("{}h", secs / (60 * 60)),
+        _ if secs >= 60 => format!("{}m", secs / 60),
+        _ => format!("{secs}s"),
+    }
+}
+
+define_cmd_args! {
+
+r#"Usage: /cache [option]*
+
+Albums and locations must be cached when they are sent, for /post to sync them later.
+
+Options:
+  help  : show this help message
+  +/-enable : enable / disable caching of this chat, disabling also purges the cache and turns
+              off caching messages (default: depends on the server)
+  +/-messages : cache all messages of this chat, including their edit history, for /post to sync
+                several messages at once, if caching of this chat is enabled (default: off)
+  purge : delete everything cached of this chat
+
+Changing caching of a group requires being an administrator of it.
+"#
+
+    #[derive(PartialEq, Eq, Debug, Default)]
+    pub struct CacheArgs {
+        pub help: bool,
+        pub enable: Option<bool>,
+        pub messages: Option<bool>,
+        pub purge: bool,
+    }
+}
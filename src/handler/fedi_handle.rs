@@ -0,0 +1,276 @@
+use std::sync::Arc;
+
+use spdlog::prelude::*;
+use teloxide::requests::Requester;
+
+use crate::{
+    handler::{Request, Response},
+    mastodon,
+    util::{mention, text::*},
+};
+
+const HELP: &str = r#"Usage: /fedi_handle [help | remove | verify | @you@example.com]
+
+Mentions of you in synced statuses are rewritten to your fediverse handle, so that you get notified.
+
+  /fedi_handle : show your handle
+  /fedi_handle @you@example.com : set your handle
+  /fedi_handle verify : verify your handle
+  /fedi_handle remove : remove your handle
+
+Handles of the Mastodon account linked with /auth are verified right away. For other accounts, add the code shown after setting the handle to the bio or a profile field of the account, then send /fedi_handle verify. Unverified handles are not used.
+
+Administrators of a group can also set handles of members for the group:
+
+  /fedi_handle @telegram_username @them@example.com
+  /fedi_handle remove @telegram_username
+
+Mentions of users without a known handle become plain names, or t.me links with /settings +mention_links.
+"#;
+
+pub async fn handle<'a>(
+    req: &Request,
+    arg: impl Into<String>,
+) -> Result<Response<'a>, Response<'a>> {
+    let arg = arg.into();
+    let args = arg.split_whitespace().collect::<Vec<_>>();
+    if args.first() == Some(&"help") {
+        return Ok(Response::reply_to(mtb().pre(HELP).build()));
+    }
+
+    let chat = &req.msg().chat;
+    let user = req
+        .msg()
+        .from()
+        .ok_or_else(|| Response::reply_to("No user."))?;
+
+    let invalid = |err| Response::reply_to(format!("Failed to parse arguments.\n\n{err}"));
+    let invalid_username = |username: &str| {
+        invalid(format!(
+            "'{username}' is not a Telegram username, it should look like @username."
+        ))
+    };
+
+    match args.as_slice() {
+        [] => {
+            let handle = mention::registered(req.state(), user.id)
+                .await
+                .map_err(|err| {
+                    Response::reply_to(format!("Failed to query fediverse handle.\n\n{err}"))
+                })?;
+
+            let mut resp = match handle {
+                Some(mention::RegisteredHandle {
+                    handle,
+                    verify_code: None,
+                }) => mtb().plain(format!("Your fediverse handle: @{handle}\n")),
+                Some(mention::RegisteredHandle {
+                    handle,
+                    verify_code: Some(code),
+                }) => verify_instructions(
+                    mtb().plain(format!("Your fediverse handle: @{handle} (unverified)\n\n")),
+                    &code,
+                ),
+                None => mtb().plain("You haven't set your fediverse handle.\n"),
+            };
+
+            if !chat.is_private() {
+                let mappings =
+                    mention::list_for_chat(req.state(), chat.id)
+                        .await
+                        .map_err(|err| {
+                            Response::reply_to(format!(
+                                "Failed to query fediverse handles.\n\n{err}"
+                            ))
+                        })?;
+                if !mappings.is_empty() {
+                    resp = resp.bold("\nHandles set for this group\n\n");
+                    for (username, handle) in mappings {
+                        resp = resp.plain(format!("@{username} → @{handle}\n"));
+                    }
+                }
+            }
+
+            Ok(Response::reply_to(
+                resp.plain("\nSend ")
+                    .code("/fedi_handle help")
+                    .plain(" for how to change it.")
+                    .build(),
+            ))
+        }
+        ["remove"] => {
+            let removed = mention::unregister(req.state(), user.id)
+                .await
+                .map_err(|err| {
+                    Response::reply_to(format!("Failed to remove fediverse handle.\n\n{err}"))
+                })?;
+            if removed {
+                info!("user '{}' removed their fediverse handle", user.id);
+                Ok(Response::reply_to("Your fediverse handle is removed."))
+            } else {
+                Err(Response::reply_to("You haven't set your fediverse handle."))
+            }
+        }
+        ["verify"] => {
+            let Some(registered) =
+                mention::registered(req.state(), user.id)
+                    .await
+                    .map_err(|err| {
+                        Response::reply_to(format!("Failed to query fediverse handle.\n\n{err}"))
+                    })?
+            else {
+                return Err(Response::reply_to("You haven't set your fediverse handle."));
+            };
+            let Some(code) = registered.verify_code else {
+                return Ok(Response::reply_to(format!(
+                    "Your fediverse handle @{} is already verified.",
+                    registered.handle
+                )));
+            };
+
+            // Handles set before domains were checked
+            mention::parse_handle(&registered.handle).map_err(|err| {
+                Response::reply_to(format!(
+                    "Your fediverse handle can't be verified, please set another one.\n\n{err}"
+                ))
+            })?;
+
+            let found = mastodon::profile_contains(&registered.handle, &code)
+                .await
+                .map_err(|err| {
+                    warn!(
+                        "user '{}' failed to query the profile of their fediverse handle: {err}",
+                        user.id
+                    );
+                    Response::reply_to(format!(
+                        "Failed to query the profile of @{}.\n\n{err}",
+                        registered.handle
+                    ))
+                })?;
+            if !found {
+                return Err(Response::reply_to(
+                    verify_instructions(
+                        mtb().plain(format!(
+                            "The code was not found in the profile of @{}.\n\n",
+                            registered.handle
+                        )),
+                        &code,
+                    )
+                    .build(),
+                ));
+            }
+
+            mention::verify(req.state(), user.id).await.map_err(|err| {
+                error!(
+                    "user '{}' failed to verify fediverse handle: {err}",
+                    user.id
+                );
+                Response::reply_to(format!("Failed to save fediverse handle.\n\n{err}"))
+            })?;
+
+            info!("user '{}' verified their fediverse handle", user.id);
+            Ok(Response::reply_to(format!(
+                "Your fediverse handle @{} is verified, the code can be removed from the profile now.",
+                registered.handle
+            )))
+        }
+        [handle] => {
+            let handle = mention::parse_handle(handle).map_err(|err| invalid(err.to_string()))?;
+
+            let client = mastodon::Client::new(Arc::clone(req.state()));
+            let linked = match client.login(user.id).await {
+                Ok(login_user) => login_user.owns_handle(&handle).await.unwrap_or_else(|err| {
+                    warn!(
+                        "user '{}' failed to query their mastodon account: {err}",
+                        user.id
+                    );
+                    false
+                }),
+                Err(_) => false,
+            };
+
+            let registered = mention::register(req.state(), user, &handle, linked)
+                .await
+                .map_err(|err| {
+                    error!("user '{}' failed to set fediverse handle: {err}", user.id);
+                    Response::reply_to(format!("Failed to save fediverse handle.\n\n{err}"))
+                })?;
+
+            info!(
+                "user '{}' set their fediverse handle, verified: {linked}",
+                user.id
+            );
+            Ok(Response::reply_to(match registered.verify_code {
+                None => mtb()
+                    .plain(format!("Your fediverse handle is set to @{handle}."))
+                    .build(),
+                Some(code) => verify_instructions(
+                    mtb().plain(format!(
+                        "Your fediverse handle is set to @{handle}, but it's not verified yet.\n\n"
+                    )),
+                    &code,
+                )
+                .build(),
+            }))
+        }
+        ["remove", username] | [username, _] => {
+            if chat.is_private() {
+                return Err(Response::reply_to(
+                    "Handles of other users can only be set in groups, by administrators.",
+                ));
+            }
+            let member = req
+                .bot()
+                .get_chat_member(chat.id, user.id)
+                .await
+                .map_err(|err| {
+                    Response::reply_to(format!("Failed to query chat member.\n\n{err}"))
+                })?;
+            if !member.kind.is_privileged() {
+                return Err(Response::reply_to(
+                    "Only administrators of the chat can set handles of other users.",
+                ));
+            }
+
+            let tg_username =
+                mention::parse_username(username).ok_or_else(|| invalid_username(username))?;
+            let handle = match args.as_slice() {
+                ["remove", _] => None,
+                [_, handle] => {
+                    Some(mention::parse_handle(handle).map_err(|err| invalid(err.to_string()))?)
+                }
+                _ => unreachable!(),
+            };
+
+            let existed =
+                mention::set_for_chat(req.state(), chat.id, &tg_username, handle.as_deref())
+                    .await
+                    .map_err(|err| {
+                        error!(
+                            "failed to set fediverse handle for chat '{}': {err}",
+                            chat.id
+                        );
+                        Response::reply_to(format!("Failed to save fediverse handle.\n\n{err}"))
+                    })?;
+
+            info!(
+                "user '{}' changed the fediverse handle of '@{tg_username}' for chat '{}'",
+                user.id, chat.id
+            );
+            Ok(Response::reply_to(match handle {
+                Some(handle) => format!("Fediverse handle of @{tg_username} is set to @{handle}."),
+                None if existed => format!("Fediverse handle of @{tg_username} is removed."),
+                None => format!("@{tg_username} has no fediverse handle set for this group."),
+            }))
+        }
+        _ => Err(Response::reply_to(mtb().pre(HELP).build())),
+    }
+}
+
+fn verify_instructions<'a>(resp: MessageTextBuilder<'a>, code: &str) -> MessageTextBuilder<'a> {
+    resp.plain("Add ")
+        .code(code)
+        .plain(" to the bio or a profile field of the account, then send ")
+        .code("/fedi_handle verify")
+        .plain(". Mentions are not rewritten to unverified handles.\n")
+}
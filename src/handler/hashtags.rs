@@ -0,0 +1,149 @@
+use spdlog::prelude::*;
+
+use crate::{
+    cmd::{define_cmd_args, Args},
+    handler::{Request, Response},
+    settings::{HashtagRules, UserSettings},
+    util::{hashtag, text::*},
+};
+
+pub async fn handle<'a>(
+    req: &Request,
+    arg: impl Into<String>,
+) -> Result<Response<'a>, Response<'a>> {
+    let args = HashtagsArgs::parse(arg.into())
+        .map_err(|err| Response::reply_to(format!("Failed to parse arguments.\n\n{err}")))?;
+    if args.help {
+        return Ok(Response::reply_to(mtb().pre(HashtagsArgs::help()).build()));
+    }
+
+    let user = req
+        .msg()
+        .from()
+        .ok_or_else(|| Response::reply_to("No user."))?;
+
+    let mut settings = UserSettings::load(req.state(), user.id)
+        .await
+        .map_err(|err| Response::reply_to(format!("Failed to load settings.\n\n{err}")))?;
+    let old_rules = settings.hashtags.clone();
+    let rules = &mut settings.hashtags;
+
+    if args.reset {
+        *rules = HashtagRules::default();
+    }
+
+    if let Some(append) = &args.append {
+        rules.append = split(append)
+            .map(parse_tag)
+            .collect::<Result<_, _>>()
+            .map_err(Response::reply_to)?;
+    }
+
+    if let Some(map) = &args.map {
+        for pair in split(map) {
+            let (from, to) = pair.split_once(':').ok_or_else(|| {
+                Response::reply_to(format!(
+                    "Failed to parse arguments.\n\n'{pair}' is not in the form of #from:#to."
+                ))
+            })?;
+            rules.map.insert(
+                parse_tag(from).map_err(Response::reply_to)?.to_lowercase(),
+                parse_tag(to).map_err(Response::reply_to)?,
+            );
+        }
+    }
+
+    if let Some(unmap) = &args.unmap {
+        for tag in split(unmap) {
+            let tag = parse_tag(tag).map_err(Response::reply_to)?;
+            rules.map.remove(&tag.to_lowercase());
+        }
+    }
+
+    if let Some(move_to_end) = args.move_to_end {
+        rules.move_to_end = move_to_end;
+    }
+
+    if settings.hashtags != old_rules {
+        settings
+            .save(req.state(), user.id)
+            .await
+            .map_err(|err| Response::reply_to(format!("Failed to save settings.\n\n{err}")))?;
+        info!("user '{}' changed hashtag rules", user.id);
+    }
+
+    let rules = &settings.hashtags;
+    let append = rules
+        .append
+        .iter()
+        .map(|tag| format!("#{tag}"))
+        .collect::<Vec<_>>();
+    let map = rules
+        .map
+        .iter()
+        .map(|(from, to)| format!("  #{from} → #{to}\n"))
+        .collect::<String>();
+
+    Ok(Response::reply_to(
+        mtb()
+            .bold("Your hashtag rules\n\n")
+            .plain(format!(
+                "append: {}\n",
+                if append.is_empty() {
+                    "-".into()
+                } else {
+                    append.join(" ")
+                }
+            ))
+            .plain(format!("map: {}\n", if map.is_empty() { "-" } else { "" }))
+            .plain(map)
+            .plain(format!(
+                "move_to_end: {}\n",
+                if rules.move_to_end { "on" } else { "off" }
+            ))
+            .plain("\nSend ")
+            .code("/hashtags help")
+            .plain(" for how to change them.")
+            .build(),
+    ))
+}
+
+fn split(tags: &str) -> impl Iterator<Item = &str> {
+    tags.split(',').map(str::trim).filter(|tag| !tag.is_empty())
+}
+
+fn parse_tag(tag: &str) -> Result<String, String> {
+    hashtag::normalize(tag).ok_or_else(|| {
+        format!(
+            "Failed to parse arguments.\n\n'{tag}' is not a valid hashtag, it can only contain letters, digits and _."
+        )
+    })
+}
+
+define_cmd_args! {
+
+r#"Usage: /hashtags [option]*
+
+Hashtags of synced statuses are always cleaned up, `#tag@channel` suffixes are removed and spaces
+are added around hashtags glued to other words, so that Mastodon can recognize them. The rules
+below are per user, they apply to what you sync in any chat and not to other users.
+
+Options:
+  help  : show this help message
+  append=<#tag,...> : hashtags appended to every status, if not in the text yet, `append=` to clear
+  map=<#from:#to,...> : replace Telegram hashtags with other hashtags, e.g. map=#新闻:#news
+  unmap=<#from,...> : remove replacements
+  +/-move_to_end : move all hashtags of the text to the end of the status (default: off)
+  reset : remove all rules
+"#
+
+    #[derive(PartialEq, Eq, Debug, Default)]
+    pub struct HashtagsArgs {
+        pub help: bool,
+        pub append: Option<String>,
+        pub map: Option<String>,
+        pub unmap: Option<String>,
+        pub move_to_end: Option<bool>,
+        pub reset: bool,
+    }
+}
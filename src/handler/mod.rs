@@ -1,22 +1,33 @@
 mod auth;
 mod broadcast;
+mod cache;
 #[cfg(debug_assertions)]
 mod debug;
+mod fedi_handle;
+mod hashtags;
 mod ping;
-mod post;
+pub(crate) mod post;
+mod rules_text;
+mod settings;
 mod start;
+mod template;
 
 use std::{env, sync::Arc};
 
 use spdlog::prelude::*;
-use teloxide::{payloads::SendMessageSetters, prelude::*, types::ChatKind};
+use teloxide::{
+    payloads::SendMessageSetters,
+    prelude::*,
+    types::{CallbackQuery, ChatKind, ChatMemberUpdated},
+};
 
 use crate::{
     cmd::Command,
     config,
     util::{
+        self,
         handle::{self, RequestKind::*, Response, ResponseKind::*},
-        media,
+        media, msgcache,
         text::*,
         ProgMsg,
     },
@@ -52,6 +63,36 @@ pub async fn handle(req: Request) -> Result<(), teloxide::RequestError> {
     Ok(())
 }
 
+pub async fn handle_callback_query(
+    bot: Bot,
+    query: CallbackQuery,
+) -> Result<(), teloxide::RequestError> {
+    let Some(answer) = util::on_cancel_query(&query) else {
+        debug!("unhandled callback query: {:?}", query.data);
+        return Ok(());
+    };
+
+    bot.answer_callback_query(query.id).text(answer).await?;
+    Ok(())
+}
+
+// Purges the cache of chats the bot left or was removed from
+pub async fn handle_my_chat_member(
+    state: Arc<InstanceState>,
+    update: ChatMemberUpdated,
+) -> Result<(), teloxide::RequestError> {
+    let chat_id = update.chat.id;
+    if update.new_chat_member.kind.is_present() {
+        return Ok(());
+    }
+
+    match crate::cache::forget_chat(&state, chat_id).await {
+        Ok(deleted) => info!("left chat '{chat_id}', purged {deleted} cached message(s)"),
+        Err(err) => error!("failed to purge cache of left chat '{chat_id}': {err}"),
+    }
+    Ok(())
+}
+
 async fn handle_kind(req: &Request) -> Result<Response<'_>, Response<'_>> {
     match req.kind() {
         NewMessage => handle_new_message(req).await,
@@ -68,6 +109,7 @@ async fn handle_new_message(req: &Request) -> Result<Response<'_>, Response<'_>>
     );
 
     media::on_new_or_edited_message(req.state(), req.msg()).await;
+    msgcache::on_new_or_edited_message(req.state(), req.msg(), false).await;
     Ok(Response::nothing())
 }
 
@@ -79,6 +121,7 @@ async fn handle_edited_message(req: &Request) -> Result<Response<'_>, Response<'
     );
 
     media::on_new_or_edited_message(req.state(), req.msg()).await;
+    msgcache::on_new_or_edited_message(req.state(), req.msg(), true).await;
     Ok(Response::nothing())
 }
 
@@ -104,12 +147,22 @@ async fn handle_command<'a>(
             let res = post::handle(req, &mut prog_msg, arg).await;
             prog_msg.map_res(res).await
         }
+        Command::Settings(arg) => settings::handle(req, arg).await,
+        Command::Template(arg) => template::handle(req, arg).await,
+        Command::Hashtags(arg) => hashtags::handle(req, arg).await,
+        Command::FediHandle(arg) => fedi_handle::handle(req, arg).await,
+        Command::RulesText(arg) => rules_text::handle(req, arg).await,
+        Command::Cache(arg) => cache::handle(req, arg).await,
         Command::Broadcast(arg) => {
             require_admin(req)?;
             let mut prog_msg = ProgMsg::new(req.bot(), req.msg(), "Broadcasting...");
             let res = broadcast::handle(req, &mut prog_msg, arg).await;
             prog_msg.map_res(res).await
         }
+        Command::CacheStats => {
+            require_admin(req)?;
+            cache::stats(req).await
+        }
     }
 }
 
@@ -0,0 +1,1552 @@
+mod poll;
+mod quote;
+mod thread;
+
+use std::{
+    borrow::Cow, fmt, future::Future, io::Cursor, mem, path::Path, sync::Arc, time::Duration,
+};
+
+use anyhow::anyhow;
+use futures_util::{future, StreamExt};
+use lingua::{Language, LanguageDetector, LanguageDetectorBuilder};
+use once_cell::sync::Lazy;
+use spdlog::prelude::*;
+use teloxide::{
+    net::Download,
+    prelude::*,
+    requests::Requester,
+    types::{
+        File, FileMeta, ForwardedFrom, MediaKind::*, Message, MessageEntityKind, MessageId,
+        Sticker, StickerFormat, User,
+    },
+};
+use tokio::{fs, io, sync::watch, task, time};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    cache,
+    cmd::{define_cmd_args, parse_duration, Args},
+    config,
+    handler::{Request, Response},
+    mastodon::{self, Language as MLanguage, *},
+    settings::UserSettings,
+    util::{
+        self, hashtag,
+        media::{self, convert, Media, MediaKind},
+        mention, msgcache, sync_record,
+        template::{self, Template, Var},
+        text::*,
+        text_rule, ProgMsg,
+    },
+    InstanceState,
+};
+
+struct Upload<'a> {
+    media: &'a MediaKind,
+    file: &'a FileMeta,
+    thumbnail: Option<&'a FileMeta>,
+    description: Option<String>,
+}
+
+fn filter_media<'a>(media: &'a MediaKind, config: &InstanceConfig) -> Result<Upload<'a>, String> {
+    let Some(file) = media.file() else {
+        return Err(format!("{} is not supported", media.kind_name()));
+    };
+
+    let is_supported_mime_type = |mime_type: Option<&str>| {
+        mime_type
+            .map(|mime_type| config.media_attachments.is_supported(mime_type))
+            .unwrap_or(true)
+    };
+
+    match media.inner() {
+        Animation(_) | Photo(_) | Sticker(_) | Video(_) | VideoNote(_) => {}
+        Audio(_) | Voice(_) => {
+            if !is_supported_mime_type(media.mime_type()) {
+                return Err(format!(
+                    "{} format '{}' is not supported by the instance",
+                    media.kind_name(),
+                    media.mime_type().unwrap_or_default()
+                ));
+            }
+        }
+        Document(_) => {
+            let mime_type = media.mime_type();
+            let is_media = mime_type
+                .map(|mime_type| {
+                    ["image/", "video/", "audio/"]
+                        .iter()
+                        .any(|prefix| mime_type.starts_with(prefix))
+                })
+                .unwrap_or(false);
+
+            if !is_media || !is_supported_mime_type(mime_type) {
+                return Err(format!(
+                    "document '{}' ({}) is not an image, video or audio supported by the instance",
+                    media.file_name().unwrap_or("untitled"),
+                    mime_type.unwrap_or("unknown type")
+                ));
+            }
+        }
+        Contact(_) | Game(_) | Venue(_) | Location(_) | Poll(_) | Text(_) | Migration(_) => {
+            return Err(format!("{} is not supported", media.kind_name()));
+        }
+    }
+
+    Ok(Upload {
+        media,
+        file,
+        thumbnail: media.thumbnail(),
+        description: media.description(),
+    })
+}
+
+pub(crate) async fn instance_config(login_user: &LoginUser) -> InstanceConfig {
+    login_user.instance_config().await.unwrap_or_else(|err| {
+        warn!(
+            "failed to query instance config for domain '{}', fallback to defaults: {err}",
+            login_user.domain()
+        );
+        Default::default()
+    })
+}
+
+struct Context<'a> {
+    req: &'a Request,
+    args: &'a PostArgs,
+    settings: &'a UserSettings,
+    user: &'a User,
+    login_user: &'a LoginUser,
+    poll_expires: Option<Duration>,
+}
+
+struct Composed {
+    status: NewStatus,
+    poll: Option<NewPoll>,
+    lang: Option<MLanguage>,
+    // Shown in the reply, e.g. "en, w/ src"
+    info: String,
+    warnings: Vec<String>,
+    // Media the instance is still processing, only if allowed
+    pending_media: Vec<AttachmentId>,
+}
+
+pub async fn handle<'a>(
+    req: &Request,
+    prog_msg: &mut ProgMsg<'a>,
+    arg: impl Into<String>,
+) -> Result<Response<'a>, Response<'a>> {
+    let args = PostArgs::parse(arg.into())
+        .map_err(|err| Response::reply_to(format!("Failed to parse arguments.\n\n{err}")))?;
+    if args.help {
+        return Ok(Response::reply_to(PostArgs::help()));
+    }
+    let poll_expires = args
+        .poll_expires
+        .as_ref()
+        .map(parse_duration)
+        .transpose()
+        .map_err(|err| Response::reply_to(format!("Failed to parse arguments.\n\n{err}")))?;
+
+    let user = req
+        .msg()
+        .from()
+        .ok_or_else(|| Response::reply_to("No user."))?;
+
+    if let Err(err) = cache::mark_used(req.state(), req.msg().chat.id).await {
+        warn!("failed to mark chat '{}' as used: {err}", req.msg().chat.id);
+    }
+
+    let Some(reply_to_msg) = req.msg().reply_to_message() else {
+        return Ok(Response::reply_to(mtb().pre(PostArgs::help()).build()));
+    };
+
+    let client = mastodon::Client::new(Arc::clone(req.state()));
+    let login_user = client.login(user.id).await.map_err(|err| {
+        warn!("user '{}' login mastodon failed: {err}", user.id);
+        Response::reply_to("Please use /auth to link your mastodon account first.")
+    })?;
+
+    info!("user '{}' trying to post on mastodon", user.id);
+
+    let settings = UserSettings::load(req.state(), user.id)
+        .await
+        .unwrap_or_else(|err| {
+            warn!(
+                "failed to load settings of user '{}', fallback to defaults: {err}",
+                user.id
+            );
+            Default::default()
+        });
+    let background_processing = args
+        .background_processing
+        .unwrap_or(settings.background_processing);
+    let ctx = Context {
+        req,
+        args: &args,
+        settings: &settings,
+        user,
+        login_user: &login_user,
+        poll_expires,
+    };
+
+    if args.count.is_some() || args.until.is_some() {
+        if args.version.is_some() {
+            return Err(Response::reply_to(
+                "Failed to parse arguments.\n\nversion can't be used together with count or until.",
+            ));
+        }
+        return thread::handle(&ctx, prog_msg, reply_to_msg).await;
+    }
+
+    let earlier_version;
+    let reply_to_msg = match &args.version {
+        Some(version) => {
+            earlier_version = message_version(req, reply_to_msg, version).await?;
+            &earlier_version
+        }
+        None => reply_to_msg,
+    };
+
+    let media = Media::query(req.state(), reply_to_msg, prog_msg)
+        .await
+        .map_err(|err| {
+            error!("user '{}' failed to query media: {err}", user.id);
+            Response::reply_to(format!("Failed to query media.\n\n{err}"))
+        })?;
+
+    let Some(Composed {
+        status,
+        poll: new_poll,
+        lang,
+        info,
+        mut warnings,
+        pending_media,
+    }) = compose(
+        &ctx,
+        prog_msg,
+        reply_to_msg,
+        media,
+        None,
+        background_processing,
+    )
+    .await?
+    else {
+        return Ok(Response::reply_to("Cancelled."));
+    };
+
+    if !pending_media.is_empty() {
+        info!(
+            "user '{}' is waiting for {} media to be processed in background",
+            user.id,
+            pending_media.len()
+        );
+
+        post_in_background(
+            req.bot().clone(),
+            Arc::clone(req.state()),
+            login_user.clone(),
+            req.msg(),
+            reply_to_msg.clone(),
+            prog_msg.msg_id(),
+            status,
+            pending_media,
+            info,
+            warnings,
+        );
+
+        return Ok(Response::reply_to(
+            "⏳ The instance is still processing the media, the status will be posted once it's done.",
+        ));
+    }
+
+    prog_msg.update("Posting status...", true).await;
+
+    let has_poll = new_poll.is_some();
+
+    let posted = login_user
+        .post_status(status, new_poll)
+        .await
+        .map_err(|err| {
+            error!("user '{}' failed to post status: {err}", user.id);
+            Response::reply_to(format!("Failed to post status on mastodon.\n\n{err}"))
+        })?;
+
+    info!(
+        "tg user '{}' posted a status: {} ({lang:?})",
+        login_user.tg_user_id(),
+        posted.url
+    );
+    sync_record::record(req.state(), &login_user, [reply_to_msg], &posted).await;
+
+    if has_poll && args.poll_results == Some(true) {
+        warnings.extend(
+            poll::schedule_results(
+                req.bot().clone(),
+                Arc::clone(req.state()),
+                login_user.clone(),
+                reply_to_msg,
+                &posted,
+            )
+            .await,
+        );
+    }
+
+    Ok(Response::reply_to(format_posted(
+        &info,
+        &posted.url,
+        warnings,
+    )))
+}
+
+// Versions of an edited message are numbered from the oldest one cached, the
+// latest version is the message itself
+async fn message_version<'r>(
+    req: &Request,
+    msg: &Message,
+    version: &str,
+) -> Result<Message, Response<'r>> {
+    let version = version
+        .parse::<usize>()
+        .ok()
+        .filter(|version| *version >= 1)
+        .ok_or_else(|| {
+            Response::reply_to("Failed to parse arguments.\n\nversion must be a positive number.")
+        })?;
+
+    let cached = msgcache::query(req.state(), msg.chat.id, msg.id)
+        .await
+        .map_err(|err| {
+            error!("failed to query cached message: {err}");
+            Response::reply_to(format!("Failed to query cached messages.\n\n{err}"))
+        })?
+        .ok_or_else(|| {
+            Response::reply_to(
+                "The message is not cached, it must be sent after enabling /cache +messages.",
+            )
+        })?;
+
+    let versions = cached.edits.len() + 1;
+    cached
+        .edits
+        .into_iter()
+        .chain([cached.msg])
+        .nth(version - 1)
+        .ok_or_else(|| {
+            Response::reply_to(format!(
+                "The message has {versions} cached version(s), version must be at most {versions}."
+            ))
+        })
+}
+
+// The status is composed from the message, but not posted yet. Returns `None`
+// if the user cancelled it.
+async fn compose<'r>(
+    ctx: &Context<'_>,
+    prog_msg: &mut ProgMsg<'_>,
+    msg: &Message,
+    media: Option<Media>,
+    in_reply_to: Option<&str>,
+    allow_pending: bool,
+) -> Result<Option<Composed>, Response<'r>> {
+    let Context {
+        req,
+        args,
+        settings,
+        user,
+        login_user,
+        poll_expires,
+    } = *ctx;
+    let partial = args.partial.unwrap_or(settings.partial);
+
+    let mut status = StatusBuilder::new();
+
+    status.visibility(Visibility::Public);
+    if let Some(in_reply_to) = in_reply_to {
+        status.in_reply_to(in_reply_to);
+    }
+
+    let tg_poll = media.as_ref().and_then(|media| {
+        media.iter().find_map(|media| match media.inner() {
+            Poll(m) => Some(&m.poll),
+            _ => None,
+        })
+    });
+    let location_text = media.as_ref().and_then(|media| match media {
+        Media::Single(media) => media.location_text(),
+        Media::Group { .. } => None,
+    });
+    let mut new_poll = None;
+    let mut warnings = vec![];
+    let mut doc_notes = vec![];
+    let mut omitted = vec![];
+    let mut has_attachments = false;
+    let mut pending_media = vec![];
+
+    let (text, entities) = if let Some(tg_poll) = tg_poll {
+        let config = instance_config(login_user).await;
+
+        let converted =
+            poll::convert(tg_poll, msg, &config.polls, poll_expires).map_err(|err| {
+                error!("user '{}' failed to convert poll: {err}", user.id);
+                Response::reply_to(format!("Failed to convert poll.\n\n{err}"))
+            })?;
+
+        new_poll = Some(converted.poll);
+        warnings.extend(converted.warnings);
+
+        (Some(tg_poll.question.as_str()), None)
+    } else if let Some(location_text) = location_text.as_deref() {
+        (Some(location_text), None)
+    } else if let Some(media) = media.as_ref() {
+        let config = instance_config(login_user).await;
+
+        let mut uploads = Vec::with_capacity(media.len());
+        for media in media.iter() {
+            match filter_media(media, &config) {
+                Ok(upload) => uploads.push(upload),
+                Err(_) if args.doc_link == Some(true) && matches!(media.inner(), Document(_)) => {
+                    doc_notes.push(media.file_name().unwrap_or("Untitled document"));
+                }
+                Err(reason) if partial => {
+                    info!("user '{}' omitted an unsupported media: {reason}", user.id);
+                    omitted.push((media.kind_name(), reason));
+                }
+                Err(reason) => {
+                    error!(
+                        "user '{}' trying to sync an unsupported media: {reason}",
+                        user.id
+                    );
+                    return Err(Response::reply_to(format!(
+                        "Contains unsupported media, {reason}."
+                    )));
+                }
+            }
+        }
+
+        info!("uploading media for user '{}'", user.id);
+
+        let (states_tx, states_rx) = watch::channel(vec![UploadState::Waiting; uploads.len()]);
+        let states_tx = Arc::new(states_tx);
+        let finished = CancellationToken::new();
+        let cancel = prog_msg.cancellable();
+
+        let (attachments, _) = tokio::join!(
+            async {
+                let uploads = uploads.into_iter().enumerate().map(|(i, upload)| {
+                    let states_tx = Arc::clone(&states_tx);
+                    upload_media(
+                        req.bot(),
+                        login_user,
+                        &config.media_attachments,
+                        settings.strip_metadata,
+                        upload,
+                        move |state| states_tx.send_modify(|states| states[i] = state),
+                    )
+                });
+                // Attachments are still returned in the order of the album.
+                // Dropping the futures on cancellation aborts
+                // in-flight transfers.
+                let attachments = tokio::select! {
+                    attachments = future::try_join_all(uploads) => Some(attachments),
+                    _ = cancel.cancelled() => None,
+                };
+                finished.cancel();
+                attachments
+            },
+            report_upload_states(prog_msg, states_rx, &finished)
+        );
+        prog_msg.finish_cancellable();
+        let states = states_tx.borrow().clone();
+
+        let attachments = match attachments {
+            Some(Ok(attachments)) => attachments,
+            Some(Err(err)) => {
+                error!("user '{}' failed to upload media: {err}", user.id);
+                delete_uploaded(login_user, &states).await;
+                return Err(Response::reply_to(format!(
+                    "Failed to upload media.\n\n{err}"
+                )));
+            }
+            None => {
+                info!("user '{}' cancelled uploading media", user.id);
+                delete_uploaded(login_user, &states).await;
+                return Ok(None);
+            }
+        };
+
+        let media_ids = attachments
+            .into_iter()
+            .map(|attached| match attached {
+                AttachedMedia::Processed(attachment) => attachment.id,
+                AttachedMedia::Processing(id) => {
+                    pending_media.push(id.clone());
+                    id
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if !pending_media.is_empty() && !allow_pending {
+            error!(
+                "user '{}' failed to upload media: timeout waiting for server processing media",
+                user.id
+            );
+            delete_uploaded(login_user, &states).await;
+            return Err(Response::reply_to(
+                "Failed to upload media.\n\nThe instance didn't finish processing the media in time. \
+                 Retry with +background_processing to post the status once it's done.",
+            ));
+        }
+
+        if !media_ids.is_empty() {
+            has_attachments = true;
+            status
+                .media_ids(media_ids)
+                .sensitive(media.iter().any(|media| media.has_media_spoiler()));
+        }
+
+        (media.caption(), media.entities())
+    } else {
+        (msg.text(), msg.entities())
+    };
+
+    let mut msg_text = MessageText::new(text.unwrap_or(""), entities.unwrap_or(&[]));
+    text_rule::apply(&settings.text_rules, msg.chat.id, &mut msg_text);
+
+    for doc_name in doc_notes {
+        if !msg_text.text().is_empty() {
+            msg_text.append_text("\n\n");
+        }
+        msg_text.append_text("📎 ");
+        msg_text
+            .append_text_link_fallback(doc_name, util::text::message_public_url(&msg.chat, msg.id));
+    }
+
+    if let (Some(true), Some(media)) = (args.location, media.as_ref()) {
+        let location =
+            media::query_adjacent_location(req.state(), msg.chat.id, media.msg_id_range(msg))
+                .await
+                .map_err(|err| {
+                    error!("user '{}' failed to query location: {err}", user.id);
+                    Response::reply_to(format!("Failed to query location.\n\n{err}"))
+                })?;
+
+        match location.and_then(|location| location.location_text()) {
+            Some(location_text) => {
+                if !msg_text.text().is_empty() {
+                    msg_text.append_text("\n\n");
+                }
+                msg_text.append_text(location_text);
+            }
+            None => warnings.push("No location was found next to the message.".into()),
+        }
+    }
+
+    if !omitted.is_empty() {
+        if !has_attachments && new_poll.is_none() && msg_text.text().trim().is_empty() {
+            return Err(Response::reply_to(format!(
+                "Nothing left to sync after omitting unsupported media.\n\n{}",
+                format_omitted(&omitted)
+            )));
+        }
+
+        if settings.partial_note {
+            let mut kinds = omitted.iter().map(|(kind, _)| *kind).collect::<Vec<_>>();
+            kinds.sort_unstable();
+            kinds.dedup();
+
+            if !msg_text.text().is_empty() {
+                msg_text.append_text("\n\n");
+            }
+            msg_text.append_text(format!(
+                "({} item(s) not synced: {})",
+                omitted.len(),
+                kinds.join(", ")
+            ));
+        }
+
+        warnings.push(format_omitted(&omitted));
+    }
+
+    prog_msg.update("Detecting content language...", true).await;
+    let lang = detect_lang(&msg_text);
+    if let Some(lang) = lang {
+        status.language(lang);
+    }
+
+    let source = query_source(req.bot(), args.src, msg, req.msg().from()).await;
+    let with_src = lay_out(
+        req.state(),
+        login_user,
+        settings,
+        args.link.unwrap_or(settings.link),
+        Origin {
+            chat_id: msg.chat.id,
+            source,
+            tg_link: util::text::message_url(&msg.chat, msg.id),
+            date: msg.date.format("%Y-%m-%d").to_string(),
+        },
+        &mut msg_text,
+    )
+    .await;
+    if args.quote.unwrap_or(settings.quote) {
+        if let Some(warning) = quote::prepend(ctx, msg, in_reply_to, &mut msg_text).await {
+            warnings.push(warning);
+        }
+    }
+    let (text, is_formatted) = format_text_for_mastodon(&msg_text);
+
+    status.status(text);
+    if is_formatted {
+        status.content_type("text/markdown");
+    }
+
+    let status = status.build().map_err(|err| {
+        error!("user '{}' failed to build status: {err}", user.id);
+        Response::reply_to(format!("Failed to build status.\n\n{err}"))
+    })?;
+
+    let mut info = String::new();
+    if let Some(lang) = lang {
+        info.push_str(lang.to_639_1().unwrap_or("??"));
+        info.push_str(", ")
+    }
+    info.push_str(if with_src { "w/ src" } else { "w/o src" });
+
+    Ok(Some(Composed {
+        status,
+        poll: new_poll,
+        lang,
+        info,
+        warnings,
+        pending_media,
+    }))
+}
+
+// The message a status is composed from, as far as laying it out is concerned
+pub(crate) struct Origin {
+    pub chat_id: ChatId,
+    pub source: Source,
+    // Link to the message itself, even if the chat is private
+    pub tg_link: Option<reqwest::Url>,
+    // e.g. "2021-01-01"
+    pub date: String,
+}
+
+// Rewrites links and mentions, applies the hashtag rules and lays the text out
+// with the source, following the template of the user if any. This is the part
+// of `compose` that doesn't need the Telegram message, shared with `import`.
+// Returns whether the source is included.
+pub(crate) async fn lay_out(
+    inst_state: &InstanceState,
+    login_user: &LoginUser,
+    settings: &UserSettings,
+    link: bool,
+    origin: Origin,
+    msg_text: &mut MessageText<'_>,
+) -> bool {
+    let Origin {
+        chat_id,
+        source,
+        tg_link,
+        date,
+    } = origin;
+
+    let template = settings.template.as_deref().and_then(|template| {
+        template
+            .parse::<Template>()
+            .map_err(|err| {
+                warn!(
+                    "user '{}' has an invalid template, fallback to the default layout: {err}",
+                    login_user.tg_user_id()
+                )
+            })
+            .ok()
+    });
+    sync_record::rewrite_links(inst_state, login_user, msg_text, settings.keep_tg_links).await;
+    mention::apply(inst_state, chat_id, msg_text, settings.mention_links).await;
+    let trailing_tags = hashtag::apply(&settings.hashtags, msg_text);
+
+    match template {
+        Some(template) => {
+            render_template(&template, msg_text, trailing_tags, &source, tg_link, date)
+        }
+        None => {
+            hashtag::append(msg_text, &trailing_tags);
+            append_source(msg_text, &source, link)
+        }
+    }
+}
+
+// `{hashtags}` only has the tags that are not in the text, i.e. the moved and
+// appended ones, which are appended to the text if the template doesn't use it.
+// Returns whether the source is included.
+fn render_template(
+    template: &Template,
+    msg_text: &mut MessageText<'_>,
+    trailing_tags: Vec<String>,
+    source: &Source,
+    tg_link: Option<reqwest::Url>,
+    date: String,
+) -> bool {
+    let hashtags = if template.uses(Var::Hashtags) {
+        trailing_tags
+    } else {
+        hashtag::append(msg_text, &trailing_tags);
+        vec![]
+    };
+    *msg_text = template.render(&template::Values {
+        text: mem::take(msg_text),
+        source_name: source.name.clone(),
+        source_username: source.username.clone(),
+        source_link: source.url.clone(),
+        tg_link,
+        hashtags,
+        date,
+    });
+
+    source.name.is_some() && template.uses(Var::SourceName)
+        || source.url.is_some() && template.uses(Var::SourceLink)
+}
+
+fn format_posted(info: &str, posted_url: &str, warnings: Vec<String>) -> MessageText<'static> {
+    let mut resp = mtb().plain(format!(
+        "Synchronized successfully. \n\n({info})\n{posted_url}",
+    ));
+    for warning in warnings {
+        resp = resp.plain(format!("\n\n⚠️ {warning}"));
+    }
+    resp.disable_preview().build()
+}
+
+// Waits for the instance to finish processing the media, then posts the status
+// and edits the progress message (or replies if there is none) with the result.
+// This is not persisted, so it's lost if the bot restarts in the meantime.
+#[allow(clippy::too_many_arguments)]
+fn post_in_background(
+    bot: Bot,
+    inst_state: Arc<InstanceState>,
+    login_user: LoginUser,
+    trigger_msg: &Message,
+    synced_msg: Message,
+    prog_msg_id: Option<MessageId>,
+    status: NewStatus,
+    pending_media: Vec<AttachmentId>,
+    info: String,
+    warnings: Vec<String>,
+) {
+    let (chat_id, trigger_msg_id) = (trigger_msg.chat.id, trigger_msg.id);
+
+    tokio::spawn(async move {
+        let res = async {
+            future::try_join_all(pending_media.iter().map(|id| {
+                login_user.wait_for_media(
+                    id,
+                    config::WAITING_FOR_SERVER_PROCESS_MEDIA_BACKGROUND_TIMEOUT,
+                )
+            }))
+            .await?;
+
+            login_user.post_status(status, None).await
+        }
+        .await;
+
+        let text = match res {
+            Ok(posted) => {
+                info!(
+                    "tg user '{}' posted a status in background: {}",
+                    login_user.tg_user_id(),
+                    posted.url
+                );
+                sync_record::record(&inst_state, &login_user, [&synced_msg], &posted).await;
+                format_posted(&info, &posted.url, warnings)
+            }
+            Err(err) => {
+                error!(
+                    "tg user '{}' failed to post status in background: {err}",
+                    login_user.tg_user_id()
+                );
+                for id in &pending_media {
+                    if let Err(err) = login_user.delete_media(id).await {
+                        warn!("failed to delete uploaded media '{id}': {err}");
+                    }
+                }
+                mtb()
+                    .plain(format!("⚠️ Failed to post status on mastodon.\n\n{err}"))
+                    .build()
+            }
+        };
+
+        _ = match prog_msg_id {
+            Some(msg_id) => bot
+                .edit_message_text(chat_id, msg_id, text.text())
+                .entities(text.into_entities())
+                .disable_web_page_preview(true)
+                .await
+                .map(|_| ()),
+            None => text
+                .executor(&bot)
+                .send_message(chat_id)
+                .reply_to_message_id(trigger_msg_id)
+                .await
+                .map(|_| ()),
+        };
+    });
+}
+
+fn format_omitted(omitted: &[(&str, String)]) -> String {
+    omitted.iter().fold(
+        format!("Omitted {} unsupported item(s):", omitted.len()),
+        |text, (_, reason)| text + "\n- " + reason,
+    )
+}
+
+// A local Bot API server (`--local`) returns absolute paths on its disk instead
+// of paths to download from, in which case there are no file size limits.
+fn local_path(file: &File) -> Option<&Path> {
+    let path = Path::new(&file.path);
+    path.is_absolute().then_some(path)
+}
+
+async fn download(bot: &Bot, file: &FileMeta) -> anyhow::Result<Vec<u8>> {
+    download_with_progress(bot, file, |_, _| {}).await
+}
+
+// `on_progress` is called with the number of bytes received and the total
+async fn download_with_progress(
+    bot: &Bot,
+    file: &FileMeta,
+    on_progress: impl Fn(u64, u64),
+) -> anyhow::Result<Vec<u8>> {
+    let file = bot.get_file(&file.id).await?;
+    let total = file.meta.size as u64;
+
+    if let Some(path) = local_path(&file) {
+        let data = fs::read(path).await?;
+        on_progress(data.len() as u64, total);
+        return Ok(data);
+    }
+
+    let mut data = Vec::with_capacity(file.meta.size as usize);
+    let mut stream = bot.download_file_stream(&file.path);
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk?);
+        on_progress(data.len() as u64, total);
+    }
+
+    Ok(data)
+}
+
+#[derive(Clone)]
+enum UploadState {
+    Waiting,
+    Downloading { received: u64, total: Option<u64> },
+    Transferring(UploadProgress),
+    Done(AttachmentId),
+}
+
+impl UploadState {
+    // The attachment exists on the server since then, even if not yet processed
+    fn uploaded_id(&self) -> Option<&AttachmentId> {
+        match self {
+            Self::Transferring(UploadProgress::Processing(id)) | Self::Done(id) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for UploadState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Waiting => write!(f, "waiting"),
+            Self::Downloading { received, total } => {
+                write!(f, "downloading {}", util::progress_bar(*received, *total))
+            }
+            Self::Transferring(UploadProgress::Sending { sent, total }) => {
+                write!(f, "uploading {}", util::progress_bar(*sent, *total))
+            }
+            Self::Transferring(UploadProgress::Processing(_)) => write!(f, "processing"),
+            Self::Done(_) => write!(f, "done"),
+        }
+    }
+}
+
+// Downloads a media from Telegram and uploads it to the instance. The number of
+// concurrent uploads is bounded by the permit.
+async fn upload_media(
+    bot: &Bot,
+    login_user: &LoginUser,
+    media_config: &MediaAttachmentsConfig,
+    strip_metadata: bool,
+    upload: Upload<'_>,
+    on_state: impl Fn(UploadState) + Send + Sync + 'static,
+) -> anyhow::Result<AttachedMedia> {
+    let _permit = login_user.upload_permit().await;
+
+    let on_state = Arc::new(on_state);
+    on_state(UploadState::Downloading {
+        received: 0,
+        total: Some(upload.file.size as u64),
+    });
+
+    let prepared = match upload.media.inner() {
+        Sticker(m) => Some(
+            prepare_sticker(bot, &m.sticker)
+                .await
+                .map_err(|err| anyhow!("failed to convert sticker: {err}"))?,
+        ),
+        _ if is_still_image(upload.media) => Some(
+            prepare_image(bot, upload.file, media_config, |received, total| {
+                on_state(UploadState::Downloading {
+                    received,
+                    total: Some(total),
+                })
+            })
+            .await
+            .map_err(|err| anyhow!("failed to process image: {err}"))?,
+        ),
+        _ => None,
+    };
+
+    let on_progress = {
+        let on_state = Arc::clone(&on_state);
+        move |progress| on_state(UploadState::Transferring(progress))
+    };
+
+    let attach = if let Some(data) = prepared {
+        let len = data.len() as u64;
+
+        login_user
+            .attach_media(
+                Cursor::new(data),
+                Some(len),
+                None,
+                upload.description,
+                strip_metadata,
+                on_progress,
+            )
+            .await
+            .map_err(|err| anyhow!("failed to attach media: {err}"))
+    } else {
+        let thumbnail = match upload.thumbnail {
+            Some(thumbnail) => download(bot, thumbnail)
+                .await
+                .map_err(|err| {
+                    warn!("failed to download thumbnail: {err}");
+                })
+                .ok(),
+            None => None,
+        };
+
+        let file = bot
+            .get_file(&upload.file.id)
+            .await
+            .map_err(|err| anyhow!("failed to get file meta: {err}"))?;
+        let len = Some(file.meta.size as u64);
+
+        if let Some(path) = local_path(&file) {
+            let local_file = fs::File::open(path)
+                .await
+                .map_err(|err| anyhow!("failed to open local file: {err}"))?;
+
+            login_user
+                .attach_media(
+                    local_file,
+                    len,
+                    thumbnail,
+                    upload.description,
+                    strip_metadata,
+                    on_progress,
+                )
+                .await
+                .map_err(|err| anyhow!("failed to attach media: {err}"))
+        } else {
+            let (writer, reader) = io::duplex(config::STREAMING_BUFFER_SIZE);
+            let path = &file.path;
+
+            // The write half has to be dropped to send EOF to the upload, so it's
+            // moved into the download. If the download fails, `try_join` drops
+            // the upload before it reads that EOF, aborting the request instead
+            // of finishing it with partial bytes.
+            let download = async move {
+                let mut writer = writer;
+                bot.download_file(path, &mut writer)
+                    .await
+                    .map_err(|err| anyhow!("failed to download file: {err}"))
+            };
+            let attach = async {
+                login_user
+                    .attach_media(
+                        reader,
+                        len,
+                        thumbnail,
+                        upload.description,
+                        strip_metadata,
+                        on_progress,
+                    )
+                    .await
+                    .map_err(|err| anyhow!("failed to attach media: {err}"))
+            };
+
+            future::try_join(download, attach)
+                .await
+                .map(|((), attached)| attached)
+        }
+    };
+
+    let attached = attach?;
+
+    // Still processing ones stay in the processing state
+    if let AttachedMedia::Processed(attachment) = &attached {
+        on_state(UploadState::Done(attachment.id.clone()));
+    }
+    Ok(attached)
+}
+
+// Shows the state of each upload in the progress message until `finished` is
+// cancelled.
+async fn report_upload_states(
+    prog_msg: &mut ProgMsg<'_>,
+    mut states: watch::Receiver<Vec<UploadState>>,
+    finished: &CancellationToken,
+) {
+    loop {
+        let text = states.borrow_and_update().iter().enumerate().fold(
+            "Uploading media...".to_string(),
+            |mut text, (i, state)| {
+                text.push_str(&format!("\n    {}. {state}", i + 1));
+                text
+            },
+        );
+        prog_msg.update_progress(text).await;
+
+        tokio::select! {
+            _ = finished.cancelled() => break,
+            changed = states.changed() => if changed.is_err() { break },
+        }
+        tokio::select! {
+            _ = finished.cancelled() => break,
+            _ = time::sleep_until(prog_msg.next_progress_update()) => {}
+        }
+    }
+}
+
+// Best-effort, the server cleans up unattached media after a while anyway
+async fn delete_uploaded(login_user: &LoginUser, states: &[UploadState]) {
+    let ids = states.iter().filter_map(UploadState::uploaded_id);
+
+    future::join_all(ids.map(|id| async move {
+        if let Err(err) = login_user.delete_media(id).await {
+            warn!("failed to delete uploaded media '{id}': {err}");
+        }
+    }))
+    .await;
+}
+
+// Images that the instance may reject for their size or dimensions. GIFs are
+// excluded since they are converted to videos by the server anyway.
+fn is_still_image(media: &MediaKind) -> bool {
+    match media.inner() {
+        Photo(_) => true,
+        Document(_) => media.mime_type().is_some_and(is_still_image_type),
+        _ => false,
+    }
+}
+
+pub(crate) fn is_still_image_type(mime_type: &str) -> bool {
+    matches!(mime_type, "image/jpeg" | "image/png" | "image/webp")
+}
+
+async fn prepare_image(
+    bot: &Bot,
+    file: &FileMeta,
+    config: &MediaAttachmentsConfig,
+    on_download: impl Fn(u64, u64),
+) -> anyhow::Result<Vec<u8>> {
+    fit_image(
+        download_with_progress(bot, file, on_download).await?,
+        config,
+    )
+    .await
+}
+
+pub(crate) async fn fit_image(
+    data: Vec<u8>,
+    config: &MediaAttachmentsConfig,
+) -> anyhow::Result<Vec<u8>> {
+    let (size_limit, matrix_limit) = (config.image_size_limit, config.image_matrix_limit);
+
+    task::spawn_blocking(move || {
+        Ok(convert::fit_image(&data, size_limit, matrix_limit)?.unwrap_or(data))
+    })
+    .await?
+}
+
+async fn prepare_sticker(bot: &Bot, sticker: &Sticker) -> anyhow::Result<Vec<u8>> {
+    convert_sticker(
+        &sticker.format,
+        || download(bot, &sticker.file),
+        sticker
+            .thumb
+            .as_ref()
+            .map(|thumb| || download(bot, &thumb.file)),
+    )
+    .await
+}
+
+// Mastodon doesn't handle sticker formats well, so convert them first. There is
+// no pure Rust renderer for animated (Lottie) stickers, their static thumbnails
+// are synced instead.
+pub(crate) async fn convert_sticker<F, T>(
+    format: &StickerFormat,
+    read: impl FnOnce() -> F,
+    read_thumbnail: Option<impl FnOnce() -> T>,
+) -> anyhow::Result<Vec<u8>>
+where
+    F: Future<Output = anyhow::Result<Vec<u8>>>,
+    T: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    async fn to_png(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        task::spawn_blocking(move || convert::to_png(&data)).await?
+    }
+
+    let thumbnail = || async {
+        let read_thumbnail =
+            read_thumbnail.ok_or_else(|| anyhow!("the sticker has no static thumbnail"))?;
+        to_png(read_thumbnail().await?).await
+    };
+
+    match format {
+        StickerFormat::Raster => to_png(read().await?).await,
+        StickerFormat::Video => match convert::webm_to_gif(&read().await?).await {
+            Ok(gif) => Ok(gif),
+            Err(err) => {
+                warn!("failed to convert video sticker, fallback to thumbnail: {err}");
+                thumbnail().await
+            }
+        },
+        StickerFormat::Animated => thumbnail().await,
+    }
+}
+
+pub(crate) fn format_text_for_mastodon<'a>(msg_text: &'a MessageText) -> (Cow<'a, str>, bool) {
+    if msg_text.entities().is_empty() {
+        return (msg_text.text().into(), false);
+    }
+
+    let entities = msg_text.parse_entities();
+    let mut text = msg_text.text().to_owned();
+    let mut is_formatted = false;
+
+    entities.iter().rev().for_each(|entity| {
+        if let MessageEntityKind::TextLink { url } = entity.kind() {
+            let (start, end) = (entity.start(), entity.end());
+
+            let mut end_iter = text[end..].chars();
+            if end_iter.next() != Some(' ') && end_iter.next().is_some() {
+                text.insert(end, ' ');
+            }
+
+            text.insert_str(end, &format!("]({url})"));
+
+            let mut start_iter = text[..start].chars().rev();
+            let last_char = start_iter.next();
+            if last_char.is_some() && last_char != Some(' ') {
+                text.insert_str(start, " [");
+            } else {
+                text.insert(start, '[');
+            }
+            is_formatted = true;
+        }
+    });
+
+    (text.into(), is_formatted)
+}
+
+pub(crate) const SRC_PREFIX: &str = "\n\n-----\nFrom";
+
+#[derive(Default)]
+pub(crate) struct Source {
+    pub name: Option<String>,
+    pub username: Option<String>,
+    // The channel post the message was forwarded from, or the message itself.
+    // Messages of private chats and groups have no public link.
+    pub url: Option<reqwest::Url>,
+}
+
+async fn query_source(
+    bot: &Bot,
+    enable: Option<bool>,
+    msg: &Message,
+    trigger: Option<&User>,
+) -> Source {
+    async fn forward_source(bot: &Bot, msg: &Message) -> Option<(String, Option<String>)> {
+        let forward = msg.forward()?;
+
+        if util::is_from_linked_channel(bot, msg)
+            .await
+            .unwrap_or(false)
+        {
+            return None;
+        }
+
+        Some(match &forward.from {
+            ForwardedFrom::User(user) => (user.full_name(), user.username.clone()),
+            ForwardedFrom::Chat(chat) => (
+                util::text::chat_display_name(chat).into(),
+                chat.username().map(Into::into),
+            ),
+            ForwardedFrom::SenderName(name) => (name.clone(), None),
+        })
+    }
+
+    fn sender_source(
+        trigger: Option<&User>,
+        exclude_channal: bool,
+        msg: &Message,
+    ) -> Option<(String, Option<String>)> {
+        let sender = msg.sender_chat();
+        let from = msg.from().filter(|user| {
+            (trigger.is_none() || trigger.filter(|t| user.id != t.id).is_some()) // alternative: `.is_some_and()`, still unstable
+                && !user.is_anonymous()
+                && !user.is_channel()
+        });
+
+        if sender.is_none() && from.is_none()
+            || exclude_channal && sender.map(|s| s.is_channel()).unwrap_or(false)
+        {
+            return None;
+        }
+
+        match (sender, from) {
+            (None, None) => unreachable!(),
+            (Some(chat), _) => Some((
+                util::text::chat_display_name(chat).into(),
+                chat.username().map(Into::into),
+            )),
+            (None, Some(user)) => Some((user.full_name(), user.username.clone())),
+        }
+    }
+
+    fn source_url(msg: &Message) -> Option<reqwest::Url> {
+        let forwarded = msg.forward().and_then(|forward| match &forward.from {
+            ForwardedFrom::Chat(chat) => forward
+                .message_id
+                .and_then(|id| util::text::message_public_url(chat, MessageId(id))),
+            _ => None,
+        });
+        forwarded.or_else(|| util::text::message_public_url(&msg.chat, msg.id))
+    }
+
+    let name = match enable {
+        None => {
+            // auto
+            //
+            // if-then `forward.source`
+            // else-if `sender != self && sender != channel` then `sender`
+            // else-then `ignore`
+
+            match forward_source(bot, msg).await {
+                Some(source) => Some(source),
+                None => sender_source(trigger, true, msg),
+            }
+        }
+        Some(true) => {
+            // force enable
+            //
+            // if-then `forward.source`
+            // else-then `sender`
+
+            match forward_source(bot, msg).await {
+                Some(source) => Some(source),
+                None => sender_source(None, false, msg),
+            }
+        }
+        Some(false) => {
+            // force disable
+            //
+            // `ignore`
+
+            return Source::default();
+        }
+    };
+
+    let (name, username) = name.unzip();
+    Source {
+        name,
+        username: username.flatten(),
+        url: source_url(msg),
+    }
+}
+
+// The default layout, `From "name" (@username)` with an optional link
+fn append_source(msg_text: &mut MessageText, source: &Source, link: bool) -> bool {
+    if let Some(name) = &source.name {
+        msg_text.append_text(format!("{SRC_PREFIX} \"{name}\""));
+        if let Some(username) = &source.username {
+            msg_text.append_text(format!(" (@{username})"));
+        }
+    }
+
+    let Some(url) = source.url.as_ref().filter(|_| link) else {
+        return source.name.is_some();
+    };
+    msg_text.append_text(if source.name.is_some() {
+        "\n".to_owned()
+    } else {
+        format!("{SRC_PREFIX} ")
+    });
+    msg_text.append_text_with_entity(url.as_str(), MessageEntityKind::Url);
+
+    true
+}
+
+pub(crate) fn detect_lang(msg_text: &MessageText) -> Option<MLanguage> {
+    let content = msg_text.extract_semantics();
+    if content.trim().is_empty() {
+        return None;
+    }
+
+    let lang = detect_lang_inner(content).unwrap_or_else(|| {
+        warn!("language connot be reliably detected, fallback to English");
+        Language::English
+    });
+
+    let lang_code = lang.iso_code_639_3().to_string();
+
+    Some(MLanguage::from_639_3(&lang_code).unwrap_or_else(|| {
+        error!("failed to convert ISO-639-3 '{lang_code}', fallback to English");
+        MLanguage::Eng
+    }))
+}
+
+fn detect_lang_inner(text: impl Into<String>) -> Option<Language> {
+    static DETECTOR: Lazy<LanguageDetector> =
+        Lazy::new(|| LanguageDetectorBuilder::from_languages(config::DETECT_LANGUAGES).build());
+
+    DETECTOR.detect_language_of(text)
+}
+
+define_cmd_args! {
+
+r#"Usage: reply /post [option]* to a message
+
+Options:
+  help   : show this help message
+  +/-src : force enable / disable appending message source (default: auto)
+           e.g. +src : sync with message source, including your own message
+                -src : sync without any source
+                *not-specified* (auto) : sync with message source, excluding your own message
+  +/-link : link to the original message in the source, or to the channel post it was forwarded
+            from, if it's in a public chat (default: see /settings)
+  +/-quote : if the message replies to another, quote the author and the text of it, or link to its
+             status if it was synced (default: see /settings)
+  poll_expires=<duration> : expiry of the synced poll (default: the Telegram close time, or 1d)
+           e.g. poll_expires=30m, poll_expires=12h, poll_expires=3d
+  +/-poll_results : reply the final Telegram results to the synced poll after it closes (default: disabled)
+           polls stopped by hand are checked hourly for a week
+           the bot reads the results in your private chat with it, so /start it there first
+  +/-location : append the location or venue sent right before or after the media (default: disabled)
+  +/-doc_link : represent documents that are not images, videos or audios as named notes linking to
+                the original message, instead of refusing them (default: disabled)
+  +/-partial : skip unsupported media and sync the rest, instead of refusing the whole post
+               (default: see /settings)
+  count=<number> : sync this and the following messages of the same sender as a thread, up to
+                   the given number of statuses (albums count as one)
+  until=<message link> : sync messages of the same sender from this one to the linked one as a thread
+           syncing several messages requires /cache +messages in the chat
+  version=<number> : sync an earlier version of an edited message, 1 is the oldest one cached
+           requires /cache +messages in the chat
+  +/-background_processing : if the instance is still processing the media after the timeout, keep
+                             waiting in the background and post the status once it's done
+                             (default: see /settings)
+"#
+
+    #[derive(PartialEq, Eq, Debug)]
+    pub struct PostArgs {
+        pub help: bool,
+        pub src: Option<bool>,
+        pub link: Option<bool>,
+        pub quote: Option<bool>,
+        pub poll_expires: Option<String>,
+        pub poll_results: Option<bool>,
+        pub location: Option<bool>,
+        pub doc_link: Option<bool>,
+        pub partial: Option<bool>,
+        pub background_processing: Option<bool>,
+        pub count: Option<String>,
+        pub until: Option<String>,
+        pub version: Option<String>,
+    }
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for PostArgs {
+    fn default() -> Self {
+        Self {
+            help: false,
+            src: None,
+            link: None,
+            quote: None,
+            poll_expires: None,
+            poll_results: None,
+            location: None,
+            doc_link: None,
+            partial: None,
+            background_processing: None,
+            count: None,
+            until: None,
+            version: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Borrow;
+
+    use teloxide::types::MessageEntity;
+
+    use super::*;
+    use crate::settings::HashtagRules;
+
+    #[test]
+    fn template_hashtags() {
+        let rules = HashtagRules {
+            append: vec!["news".into(), "cat".into()],
+            ..Default::default()
+        };
+        let template = "{text}\n\n{hashtags}".parse::<Template>().unwrap();
+
+        let mut msg_text = MessageText::new(
+            "meow #cat",
+            vec![MessageEntity::new(MessageEntityKind::Hashtag, 5, 4)],
+        );
+        let trailing_tags = hashtag::apply(&rules, &mut msg_text);
+        render_template(
+            &template,
+            &mut msg_text,
+            trailing_tags,
+            &Source::default(),
+            None,
+            String::new(),
+        );
+        assert_eq!(msg_text.text(), "meow #cat\n\n#news");
+    }
+
+    #[test]
+    fn test_format_text_for_mastodon() {
+        let mut msg_text = MessageText::new("", vec![]);
+
+        msg_text.append_text_link("link", "https://example.com".try_into().unwrap());
+        msg_text.append_text("def\n");
+
+        msg_text.append_text("abc");
+        msg_text.append_text_link("link", "https://example.com".try_into().unwrap());
+        msg_text.append_text("def\n");
+
+        msg_text.append_text("abc ");
+        msg_text.append_text_link("link", "https://example.com".try_into().unwrap());
+        msg_text.append_text("def\n");
+
+        msg_text.append_text("abc ");
+        msg_text.append_text_link("link", "https://example.com".try_into().unwrap());
+        msg_text.append_text(" def\n");
+
+        msg_text.append_text("abc");
+        msg_text.append_text_link("link", "https://example.com".try_into().unwrap());
+        msg_text.append_text(" def\n");
+
+        msg_text.append_text("abc");
+        msg_text.append_text_link("link", "https://example.com".try_into().unwrap());
+
+        let (formatted, is_formatted) = format_text_for_mastodon(&msg_text);
+        assert_eq!(
+            (formatted.borrow(), is_formatted),
+            (
+                r#"[link](https://example.com/) def
+abc [link](https://example.com/) def
+abc [link](https://example.com/) def
+abc [link](https://example.com/) def
+abc [link](https://example.com/) def
+abc [link](https://example.com/)"#,
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn language_detection() {
+        use MessageEntityKind::*;
+
+        {
+            let mut msg_text = MessageText::new("", vec![]);
+            msg_text.append_text("喵呜 ");
+            msg_text.append_text_with_entity("#English ", Hashtag);
+            msg_text.append_text_with_entity("#Tags ", Hashtag);
+            msg_text.append_text_with_entity("#Hello ", Hashtag);
+            msg_text.append_text_with_entity("#World ", Hashtag);
+            msg_text.append_text_with_entity("#Example", Hashtag);
+
+            // without `.extract_semantics()`, false positive
+            assert_eq!(detect_lang_inner(msg_text.text()), Some(Language::English));
+
+            // with `.extract_semantics()`
+            let result = detect_lang(&msg_text).unwrap();
+            assert_eq!(result, MLanguage::Zho);
+            assert_eq!(result.to_string(), "Chinese");
+        }
+        {
+            let mut msg_text = MessageText::new("", vec![]);
+            msg_text.append_text("  \t  \n ");
+            msg_text.append_text_with_entity("#English ", Hashtag);
+            msg_text.append_text_with_entity("#Tags ", Hashtag);
+            msg_text.append_text_with_entity("#Hello ", Hashtag);
+            msg_text.append_text_with_entity("#World ", Hashtag);
+            msg_text.append_text_with_entity("#Example", Hashtag);
+            msg_text.append_text("  \t  \n \n\n");
+
+            assert!(detect_lang(&msg_text).is_none());
+        }
+        {
+            let mut msg_text = MessageText::new("", vec![]);
+            msg_text.append_text("喵呜w");
+
+            let result = detect_lang(&msg_text).unwrap();
+            assert_eq!(result, MLanguage::Zho);
+            assert_eq!(result.to_string(), "Chinese");
+        }
+        {
+            let mut msg_text = MessageText::new("", vec![]);
+            msg_text.append_text("Meow~");
+
+            let result = detect_lang(&msg_text).unwrap();
+            assert_eq!(result, MLanguage::Eng);
+            assert_eq!(result.to_string(), "English");
+        }
+        {
+            let mut msg_text = MessageText::new("", vec![]);
+            msg_text.append_text("这是一个 test");
+
+            let result = detect_lang(&msg_text).unwrap();
+            assert_eq!(result, MLanguage::Zho);
+            assert_eq!(result.to_string(), "Chinese");
+        }
+        {
+            let mut msg_text = MessageText::new("", vec![]);
+            msg_text.append_text("The word test in Chinese is 测试");
+
+            let result = detect_lang(&msg_text).unwrap();
+            assert_eq!(result, MLanguage::Eng);
+            assert_eq!(result.to_string(), "English");
+        }
+        {
+            let mut msg_text = MessageText::new("", vec![]);
+            msg_text.append_text("こんにちは 😊");
+
+            let result = detect_lang(&msg_text).unwrap();
+            assert_eq!(result, MLanguage::Jpn);
+            assert_eq!(result.to_string(), "Japanese");
+        }
+    }
+}
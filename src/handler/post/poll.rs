@@ -0,0 +1,347 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use spdlog::prelude::*;
+use teloxide::{
+    prelude::*,
+    types::{Message, MessageId, Poll, PollType},
+};
+use tokio::time;
+
+use crate::{
+    config,
+    mastodon::{LoginUser, NewPoll, PollsConfig, PostedStatus, StatusBuilder, Visibility},
+    util::msgcache,
+    InstanceState,
+};
+
+pub struct ConvertedPoll {
+    pub poll: NewPoll,
+    pub warnings: Vec<String>,
+}
+
+pub fn convert(
+    poll: &Poll,
+    msg: &Message,
+    config: &PollsConfig,
+    expires: Option<Duration>,
+) -> anyhow::Result<ConvertedPoll> {
+    let mut warnings = vec![];
+
+    if poll.options.len() > config.max_options {
+        bail!(
+            "The poll has {} options, but the instance allows at most {}.",
+            poll.options.len(),
+            config.max_options
+        );
+    }
+
+    let options = poll
+        .options
+        .iter()
+        .map(|option| {
+            if option.text.chars().count() <= config.max_characters_per_option {
+                return option.text.clone();
+            }
+            warnings.push(format!(
+                "Option '{}' is longer than {} characters and has been truncated.",
+                option.text, config.max_characters_per_option
+            ));
+            truncate(&option.text, config.max_characters_per_option)
+        })
+        .collect();
+
+    if poll.poll_type == PollType::Quiz {
+        warnings.push(
+            "Mastodon doesn't support quizzes, it has been posted as a regular poll without the correct answer."
+                .into(),
+        );
+    }
+    if !poll.is_anonymous {
+        warnings.push(
+            "The poll shows voters on Telegram, but votes on Mastodon are always anonymous.".into(),
+        );
+    }
+    if poll.is_closed {
+        warnings.push("The poll is already closed on Telegram.".into());
+    }
+
+    let expires = expires
+        .or_else(|| telegram_remaining(poll, msg).filter(|remaining| !remaining.is_zero()))
+        .unwrap_or(config::DEFAULT_POLL_EXPIRES)
+        .as_secs();
+    let expires_in = expires.clamp(config.min_expiration, config.max_expiration);
+    if expires_in != expires {
+        warnings.push(format!(
+            "Poll expiry has been adjusted to {expires_in}s to fit the instance limits."
+        ));
+    }
+
+    Ok(ConvertedPoll {
+        poll: NewPoll {
+            options,
+            expires_in,
+            multiple: poll.allows_multiple_answers,
+        },
+        warnings,
+    })
+}
+
+// Posts the final results as a reply to the synced status once the poll is
+// closed. Polls stopped by hand are checked every
+// `POLL_RESULTS_CHECK_INTERVAL`, for `POLL_RESULTS_MAX_WAIT` at most. This is
+// not persisted, so pending results are lost if the bot restarts. Returns a
+// warning if the results can't be posted, or a note about when they will be.
+pub async fn schedule_results(
+    bot: Bot,
+    inst_state: Arc<InstanceState>,
+    login_user: LoginUser,
+    poll_msg: &Message,
+    status: &PostedStatus,
+) -> Option<String> {
+    let poll = poll_msg.poll().cloned()?;
+    let (chat_id, msg_id) = (poll_msg.chat.id, poll_msg.id);
+    let close_in = telegram_remaining(&poll, poll_msg);
+    let note = (!poll.is_closed && close_in.is_none()).then(|| {
+        format!(
+            "The Telegram poll has no close time, its results will be posted within {} after it's stopped, if that's within {}.",
+            format_duration(config::POLL_RESULTS_CHECK_INTERVAL),
+            format_duration(config::POLL_RESULTS_MAX_WAIT)
+        )
+    });
+
+    // Fail early instead of when the poll closes, there is no one to tell then
+    if !poll.is_closed {
+        if let Err(err) = bot.get_chat(login_user.tg_user_id()).await {
+            warn!(
+                "tg user '{}' can't be reached privately by the bot: {err}",
+                login_user.tg_user_id()
+            );
+            return Some(NO_PRIVATE_CHAT.into());
+        }
+    }
+
+    let status_id = status.id.clone();
+
+    tokio::spawn(async move {
+        let res = async {
+            let poll = if poll.is_closed {
+                poll
+            } else {
+                wait_closed(
+                    &bot,
+                    &inst_state,
+                    login_user.tg_user_id(),
+                    (chat_id, msg_id),
+                    close_in.unwrap_or(config::POLL_RESULTS_CHECK_INTERVAL),
+                )
+                .await?
+            };
+
+            let status = StatusBuilder::new()
+                .status(format_results(&poll))
+                .in_reply_to(&status_id)
+                .visibility(Visibility::Public)
+                .build()?;
+
+            login_user.post_status(status, None).await
+        }
+        .await;
+
+        match res {
+            Ok(posted) => info!(
+                "tg user '{}' posted poll results: {}",
+                login_user.tg_user_id(),
+                posted.url
+            ),
+            Err(err) => {
+                error!(
+                    "tg user '{}' failed to post poll results: {err}",
+                    login_user.tg_user_id()
+                );
+                _ = bot
+                    .send_message(
+                        chat_id,
+                        format!("Failed to post the final poll results on mastodon.\n\n{err}"),
+                    )
+                    .reply_to_message_id(msg_id)
+                    .disable_notification(true)
+                    .await;
+            }
+        }
+    });
+
+    note
+}
+
+// Waits for the first check, then checks at intervals until the poll is closed
+async fn wait_closed(
+    bot: &Bot,
+    inst_state: &InstanceState,
+    user_id: UserId,
+    (chat_id, msg_id): (ChatId, MessageId),
+    first_check_in: Duration,
+) -> anyhow::Result<Poll> {
+    let started = Instant::now();
+    time::sleep(first_check_in).await;
+
+    loop {
+        let poll = fetch_poll(bot, inst_state, user_id, chat_id, msg_id).await?;
+        if poll.is_closed {
+            return Ok(poll);
+        }
+        if started.elapsed() >= config::POLL_RESULTS_MAX_WAIT {
+            bail!(
+                "The poll was not closed within {}, its results will not be posted.",
+                format_duration(config::POLL_RESULTS_MAX_WAIT)
+            );
+        }
+        time::sleep(config::POLL_RESULTS_CHECK_INTERVAL).await;
+    }
+}
+
+// Rounded down to hours or days, the intervals above are whole ones
+fn format_duration(duration: Duration) -> String {
+    let hours = duration.as_secs() / 3600;
+    match hours {
+        1 => "an hour".into(),
+        _ if hours >= 24 => format!("{} days", hours / 24),
+        _ => format!("{hours} hours"),
+    }
+}
+
+const NO_PRIVATE_CHAT: &str = "The bot can only read the final results of the poll in your private chat with it, please /start the bot there first. The results will not be posted.";
+
+// The Bot API doesn't allow fetching polls that were not sent by the bot
+// itself. The message cache has the latest copy if the chat opted in, otherwise
+// a forwarded copy in the user's private chat carries the current state.
+async fn fetch_poll(
+    bot: &Bot,
+    inst_state: &InstanceState,
+    user_id: UserId,
+    chat_id: ChatId,
+    msg_id: MessageId,
+) -> anyhow::Result<Poll> {
+    match msgcache::query(inst_state, chat_id, msg_id).await {
+        Ok(cached) => {
+            if let Some(poll) = cached.as_ref().and_then(|cached| cached.msg.poll()) {
+                if poll.is_closed {
+                    return Ok(poll.clone());
+                }
+            }
+        }
+        Err(err) => warn!(
+            "failed to query cached poll. chat id '{chat_id}', msg id '{msg_id}', err: '{err}'"
+        ),
+    }
+
+    let forwarded = bot
+        .forward_message(user_id, chat_id, msg_id)
+        .disable_notification(true)
+        .await
+        .map_err(|err| anyhow!("{NO_PRIVATE_CHAT}\n\n{err}"))?;
+    _ = bot.delete_message(forwarded.chat.id, forwarded.id).await;
+
+    forwarded
+        .poll()
+        .cloned()
+        .ok_or_else(|| anyhow!("forwarded message is not a poll"))
+}
+
+fn format_results(poll: &Poll) -> String {
+    let mut text = format!(
+        "📊 Final results on Telegram ({} voters)\n",
+        poll.total_voter_count
+    );
+
+    for (i, option) in poll.options.iter().enumerate() {
+        let percentage = if poll.total_voter_count > 0 {
+            option.voter_count * 100 / poll.total_voter_count
+        } else {
+            0
+        };
+        let mark = if poll.correct_option_id == Some(i as u8) {
+            " ✅"
+        } else {
+            ""
+        };
+        text.push_str(&format!(
+            "\n{}{mark}: {} ({percentage}%)",
+            option.text, option.voter_count
+        ));
+    }
+
+    text
+}
+
+fn telegram_remaining(poll: &Poll, msg: &Message) -> Option<Duration> {
+    let close_at = poll.close_date.map(|date| date.timestamp()).or_else(|| {
+        poll.open_period
+            .map(|period| msg.date.timestamp() + period as i64)
+    })?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+
+    // Zero if the close time has passed, but the poll is not marked as closed
+    // yet
+    Some(Duration::from_secs((close_at - now).max(0) as u64))
+}
+
+pub(super) fn truncate(text: &str, max_chars: usize) -> String {
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncation() {
+        assert_eq!(truncate("abcdef", 4), "abc…");
+        assert_eq!(truncate("喵呜喵呜喵呜", 3), "喵呜…");
+    }
+
+    #[test]
+    fn remaining() {
+        let msg: Message = serde_json::from_str(
+            r#"{"message_id":1,"from":{"id":1,"is_bot":false,"first_name":"A"},"chat":{"id":-100123,"title":"T","type":"supergroup"},"date":1666666666,"poll":{"id":"1","question":"?","options":[{"text":"a","voter_count":0},{"text":"b","voter_count":0}],"is_closed":false,"total_voter_count":0,"is_anonymous":true,"type":"regular","allows_multiple_answers":false,"close_date":1666666766}}"#,
+        )
+        .unwrap();
+        let poll = msg.poll().unwrap();
+
+        assert_eq!(telegram_remaining(poll, &msg), Some(Duration::ZERO));
+        let mut manual = poll.clone();
+        manual.close_date = None;
+        assert_eq!(telegram_remaining(&manual, &msg), None);
+    }
+
+    #[test]
+    fn results() {
+        let poll: Poll = serde_json::from_str(
+            r#"{
+                "id": "1",
+                "question": "Cats or dogs?",
+                "options": [
+                    { "text": "Cats", "voter_count": 3 },
+                    { "text": "Dogs", "voter_count": 1 }
+                ],
+                "is_closed": true,
+                "total_voter_count": 4,
+                "is_anonymous": true,
+                "type": "quiz",
+                "allows_multiple_answers": false,
+                "correct_option_id": 0
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            format_results(&poll),
+            "📊 Final results on Telegram (4 voters)\n\nCats ✅: 3 (75%)\nDogs: 1 (25%)"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use spdlog::prelude::*;
+use teloxide::types::{ForwardedFrom, Message, MessageKind};
+
+use super::{instance_config, poll::truncate, Context};
+use crate::{
+    config,
+    util::{
+        msgcache,
+        sync_record::{self, SyncedStatus},
+        text::{self, *},
+    },
+};
+
+// A Telegram reply often only makes sense with the message it replies to. If
+// that message was synced to the account, its status is linked like the
+// fallback of Mastodon quotes (`RE: <url>`), otherwise the start of its text is
+// quoted above the status. Partial quotes are not available in the Bot API
+// version we use.
+
+// Returns a warning if the quote was omitted
+pub async fn prepend(
+    ctx: &Context<'_>,
+    msg: &Message,
+    in_reply_to: Option<&str>,
+    msg_text: &mut MessageText<'_>,
+) -> Option<String> {
+    let Context {
+        req,
+        args,
+        settings,
+        login_user,
+        ..
+    } = *ctx;
+
+    let parent = match parent(ctx, msg).await {
+        Ok(Some(parent)) => parent,
+        Ok(None) => return None,
+        // Most messages are not replies, so only warn if it's asked explicitly
+        Err(reason) => return (args.quote == Some(true)).then_some(reason),
+    };
+
+    let synced = sync_record::query(
+        req.state(),
+        login_user,
+        &LinkedChat::Id(parent.chat.id),
+        parent.id,
+    )
+    .await
+    .unwrap_or_else(|err| {
+        error!(
+            "failed to query synced status of message '{}' of chat '{}': {err}",
+            parent.id, parent.chat.id
+        );
+        None
+    });
+
+    match synced {
+        // The status already replies to it, e.g. in a thread
+        Some(SyncedStatus { id: Some(id), .. }) if Some(id.as_str()) == in_reply_to => None,
+        Some(synced) => {
+            msg_text.prepend(MessageText::from(format!("RE: {}\n\n", synced.url)));
+            None
+        }
+        None => {
+            let link = args
+                .link
+                .unwrap_or(settings.link)
+                .then(|| text::message_public_url(&parent.chat, parent.id))
+                .flatten();
+            let statuses = instance_config(login_user).await.statuses;
+
+            let author = author(&parent);
+            let reserved = msg_text.text().chars().count()
+                + format!("> {author}: \n\n").chars().count()
+                + link
+                    .as_ref()
+                    .map_or(0, |_| statuses.characters_reserved_per_url);
+            let max_chars = statuses
+                .max_characters
+                .saturating_sub(reserved)
+                .min(config::QUOTE_MAX_CHARS);
+            if max_chars < config::QUOTE_MIN_CHARS {
+                return Some("The quote is omitted, the status is too long.".into());
+            }
+
+            let mut quote = MessageText::from("> ");
+            quote.append_text_link_fallback(author, link);
+            quote.append_text(format!(": {}\n\n", excerpt(&parent, max_chars)));
+            msg_text.prepend(quote);
+            None
+        }
+    }
+}
+
+// The Bot API doesn't include the parent of a message replied to, so it's
+// looked up in the message cache if the message itself is not the reply
+async fn parent(ctx: &Context<'_>, msg: &Message) -> Result<Option<Message>, String> {
+    let parent = match msg.reply_to_message() {
+        Some(parent) => Some(parent.clone()),
+        None => msgcache::query(ctx.req.state(), msg.chat.id, msg.id)
+            .await
+            .map_err(|err| {
+                error!(
+                    "failed to query cached message '{}' of chat '{}': {err}",
+                    msg.id, msg.chat.id
+                );
+                format!("The quote is omitted, failed to query the message.\n\n{err}")
+            })?
+            .ok_or("The quote is omitted, the message replied to is unknown. Enable /cache +messages in the chat to quote it.")?
+            .msg
+            .reply_to_message()
+            .cloned(),
+    };
+
+    // Messages in forum topics reply to the creation of the topic
+    Ok(parent.filter(|parent| matches!(parent.kind, MessageKind::Common(_))))
+}
+
+fn author(msg: &Message) -> String {
+    if let Some(forward) = msg.forward() {
+        return match &forward.from {
+            ForwardedFrom::User(user) => user.full_name(),
+            ForwardedFrom::Chat(chat) => text::chat_display_name(chat).into(),
+            ForwardedFrom::SenderName(name) => name.clone(),
+        };
+    }
+
+    match (msg.sender_chat(), msg.from()) {
+        (Some(chat), _) => text::chat_display_name(chat).into(),
+        (None, Some(user)) => user.full_name(),
+        (None, None) => "Unknown".into(),
+    }
+}
+
+// Lines are joined, so that the quote stays a single paragraph
+fn excerpt(msg: &Message, max_chars: usize) -> String {
+    let text = match msg.text().or_else(|| msg.caption()) {
+        Some(text) => text.split_whitespace().collect::<Vec<_>>().join(" "),
+        None => "(media)".into(),
+    };
+
+    if text.chars().count() <= max_chars {
+        text
+    } else {
+        truncate(&text, max_chars)
+    }
+}
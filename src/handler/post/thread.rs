@@ -0,0 +1,405 @@
+use std::sync::Arc;
+
+use spdlog::prelude::*;
+use teloxide::types::{Message, MessageId, MessageKind};
+
+use super::{compose, poll, Composed, Context};
+use crate::{
+    cache::ChatCache,
+    config,
+    handler::Response,
+    util::{
+        handle::ResponseKind,
+        media::Media,
+        msgcache, sync_record,
+        text::{self, *},
+        ProgMsg,
+    },
+};
+
+// Syncs the message and the following ones of the same sender as a thread,
+// each status replies to the previous one.
+pub async fn handle<'a>(
+    ctx: &Context<'_>,
+    prog_msg: &mut ProgMsg<'a>,
+    first_msg: &Message,
+) -> Result<Response<'a>, Response<'a>> {
+    let (req, args, user, login_user) = (ctx.req, ctx.args, ctx.user, ctx.login_user);
+    let chat = &first_msg.chat;
+
+    let count = args
+        .count
+        .as_ref()
+        .map(|count| {
+            count
+                .parse::<usize>()
+                .ok()
+                .filter(|count| (1..=config::MAX_THREAD_LEN).contains(count))
+                .ok_or_else(|| {
+                    Response::reply_to(format!(
+                        "Failed to parse arguments.\n\ncount must be between 1 and {}.",
+                        config::MAX_THREAD_LEN
+                    ))
+                })
+        })
+        .transpose()?;
+    let last_msg_id = args
+        .until
+        .as_ref()
+        .map(|until| {
+            text::parse_message_url(until, chat)
+                .filter(|msg_id| msg_id.0 >= first_msg.id.0)
+                .ok_or_else(|| {
+                    Response::reply_to(
+                        "Failed to parse arguments.\n\nuntil must be a link to a later message in this chat.",
+                    )
+                })
+        })
+        .transpose()?;
+
+    let chat_cache = ChatCache::load(req.state(), chat.id)
+        .await
+        .map_err(|err| Response::reply_to(format!("Failed to load settings.\n\n{err}")))?;
+    if !chat_cache.messages {
+        return Err(Response::reply_to(
+            "Syncing several messages requires caching messages of this chat, \
+             enable it with /cache +messages first.",
+        ));
+    }
+
+    if let Some(last_msg_id) = last_msg_id {
+        let last_msg = msgcache::query(req.state(), chat.id, last_msg_id)
+            .await
+            .map_err(|err| {
+                error!("user '{}' failed to query cached messages: {err}", user.id);
+                Response::reply_to(format!("Failed to query cached messages.\n\n{err}"))
+            })?;
+        if last_msg.is_none() {
+            return Err(Response::reply_to(
+                "The linked message is not cached, it must be sent after enabling /cache +messages.",
+            ));
+        }
+    }
+
+    let query_failed = |err: anyhow::Error| {
+        error!("user '{}' failed to query cached messages: {err}", user.id);
+        Response::reply_to(format!("Failed to query cached messages.\n\n{err}"))
+    };
+
+    // The replied message may be a middle item of an album, the items before it
+    // belong to the first status too. Albums have consecutive message ids.
+    let mut first_msgs = vec![];
+    if let Some(media_group_id) = first_msg.media_group_id() {
+        let album_start = MessageId(
+            first_msg
+                .id
+                .0
+                .saturating_sub(config::MEDIA_GROUP_MAX_SIZE as i32 - 1),
+        );
+        let earlier = msgcache::query_range(
+            req.state(),
+            chat.id,
+            album_start..=MessageId(first_msg.id.0 - 1),
+            config::MEDIA_GROUP_MAX_SIZE,
+        )
+        .await
+        .map_err(query_failed)?;
+        first_msgs.extend(
+            earlier
+                .into_iter()
+                .filter(|msg| msg.media_group_id() == Some(media_group_id)),
+        );
+    }
+    first_msgs.push(first_msg.clone());
+
+    // Read in pages, so that a short thread doesn't load all later messages
+    let mut thread = Thread::new(first_msgs, count);
+    let last_msg_id = last_msg_id.unwrap_or(MessageId(i32::MAX));
+    let mut next_msg_id = MessageId(first_msg.id.0 + 1);
+    'pages: while next_msg_id.0 <= last_msg_id.0 {
+        let msgs = msgcache::query_range(
+            req.state(),
+            chat.id,
+            next_msg_id..=last_msg_id,
+            QUERY_PAGE_SIZE,
+        )
+        .await
+        .map_err(query_failed)?;
+
+        let is_last_page = msgs.len() < QUERY_PAGE_SIZE;
+        for msg in msgs {
+            next_msg_id = MessageId(msg.id.0.saturating_add(1));
+            if !thread.push(msg) {
+                break 'pages;
+            }
+        }
+        if is_last_page {
+            break;
+        }
+    }
+
+    let Thread {
+        items, interrupted, ..
+    } = thread;
+    if items.len() > config::MAX_THREAD_LEN {
+        return Err(Response::reply_to(format!(
+            "Too many messages, at most {} statuses can be synced at once.",
+            config::MAX_THREAD_LEN
+        )));
+    }
+
+    info!(
+        "user '{}' trying to post a thread of {} statuses",
+        user.id,
+        items.len()
+    );
+
+    let mut posted_urls = vec![];
+    let mut warnings = vec![];
+    if interrupted {
+        warnings.push(format!(
+            "The thread stopped at a message of another sender, after {} status(es).",
+            items.len()
+        ));
+    }
+    let mut in_reply_to: Option<String> = None;
+
+    for (i, item) in items.iter().enumerate() {
+        let progress = format!("{}/{}", i + 1, items.len());
+        let failed = |err| Response::reply_to(format_failed(&progress, err, &posted_urls));
+
+        prog_msg
+            .update(format!("Synchronizing {progress}..."), true)
+            .await;
+
+        let Some(Composed {
+            status,
+            poll: new_poll,
+            lang,
+            info: _,
+            warnings: item_warnings,
+            pending_media,
+        }) = compose(
+            ctx,
+            prog_msg,
+            &item[0],
+            Media::from_messages(item),
+            in_reply_to.as_deref(),
+            true,
+        )
+        .await
+        .map_err(|resp| failed(response_text(resp)))?
+        else {
+            return Ok(Response::reply_to(format_failed(
+                &progress,
+                "Cancelled.".into(),
+                &posted_urls,
+            )));
+        };
+
+        if !pending_media.is_empty() {
+            prog_msg
+                .update("Waiting for the instance to process media...", true)
+                .await;
+
+            for id in &pending_media {
+                login_user
+                    .wait_for_media(
+                        id,
+                        config::WAITING_FOR_SERVER_PROCESS_MEDIA_BACKGROUND_TIMEOUT,
+                    )
+                    .await
+                    .map_err(|err| {
+                        error!("user '{}' failed to wait for media: {err}", user.id);
+                        failed(format!("Failed to upload media.\n\n{err}"))
+                    })?;
+            }
+        }
+
+        let has_poll = new_poll.is_some();
+
+        let posted = login_user
+            .post_status(status, new_poll)
+            .await
+            .map_err(|err| {
+                error!("user '{}' failed to post status: {err}", user.id);
+                failed(format!("Failed to post status on mastodon.\n\n{err}"))
+            })?;
+
+        info!(
+            "tg user '{}' posted a status of thread: {} ({lang:?})",
+            login_user.tg_user_id(),
+            posted.url
+        );
+
+        sync_record::record(req.state(), login_user, item.iter(), &posted).await;
+
+        if has_poll && args.poll_results == Some(true) {
+            let warning = poll::schedule_results(
+                req.bot().clone(),
+                Arc::clone(req.state()),
+                login_user.clone(),
+                &item[0],
+                &posted,
+            )
+            .await;
+            warnings.extend(warning.map(|warning| format!("({progress}) {warning}")));
+        }
+
+        warnings.extend(
+            item_warnings
+                .into_iter()
+                .map(|warning| format!("({progress}) {warning}")),
+        );
+        in_reply_to = Some(posted.id);
+        posted_urls.push(posted.url);
+    }
+
+    let mut resp = mtb().plain(format!(
+        "Synchronized successfully as a thread of {} statuses.\n\n{}",
+        posted_urls.len(),
+        posted_urls.join("\n")
+    ));
+    for warning in warnings {
+        resp = resp.plain(format!("\n\n⚠️ {warning}"));
+    }
+
+    Ok(Response::reply_to(resp.disable_preview().build()))
+}
+
+const QUERY_PAGE_SIZE: usize = 50;
+
+// Each item is a message or all messages of an album, sent consecutively by the
+// same sender. Commands and service messages are skipped.
+struct Thread {
+    sender_id: Option<i64>,
+    count: Option<usize>,
+    items: Vec<Vec<Message>>,
+    // Ended at a message of another sender before reaching `count`
+    interrupted: bool,
+}
+
+impl Thread {
+    // Starts with the replied message, or all items of its album up to it. The
+    // replied message may have been sent before caching was enabled, so it's
+    // not read from the cache.
+    fn new(first_msgs: Vec<Message>, count: Option<usize>) -> Self {
+        let mut thread = Self {
+            sender_id: first_msgs.first().and_then(msgcache::sender_id),
+            count,
+            items: vec![],
+            interrupted: false,
+        };
+        for msg in first_msgs {
+            thread.push(msg);
+        }
+        thread
+    }
+
+    // Returns `false` if no more messages belong to the thread
+    fn push(&mut self, msg: Message) -> bool {
+        if !matches!(msg.kind, MessageKind::Common(_)) || is_command(&msg) {
+            return true;
+        }
+        if msgcache::sender_id(&msg) != self.sender_id {
+            self.interrupted = Some(self.items.len()) != self.count;
+            return false;
+        }
+
+        let same_album = msg.media_group_id().is_some()
+            && self
+                .items
+                .last()
+                .and_then(|item| item.last())
+                .and_then(|last| last.media_group_id())
+                == msg.media_group_id();
+        if let Some(item) = self.items.last_mut().filter(|_| same_album) {
+            item.push(msg);
+            return true;
+        }
+        if Some(self.items.len()) == self.count {
+            return false;
+        }
+        self.items.push(vec![msg]);
+        // One more than the limit is enough to refuse it
+        self.items.len() <= config::MAX_THREAD_LEN
+    }
+}
+
+fn is_command(msg: &Message) -> bool {
+    msg.text().is_some_and(|text| text.starts_with('/'))
+}
+
+fn response_text(resp: Response<'_>) -> String {
+    match resp.kind {
+        ResponseKind::ReplyTo(text) | ResponseKind::NewMsg(text) => text.text().into(),
+        ResponseKind::Nothing => String::new(),
+    }
+}
+
+fn format_failed(progress: &str, err: String, posted_urls: &[String]) -> String {
+    let mut text = format!("{err}\n\n(while synchronizing {progress})");
+    if !posted_urls.is_empty() {
+        text.push_str(&format!("\n\nAlready posted:\n{}", posted_urls.join("\n")));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(id: i32, sender_id: u64, text: &str, media_group_id: Option<&str>) -> Message {
+        let mut msg = serde_json::json!({
+            "message_id": id,
+            "from": { "id": sender_id, "is_bot": false, "first_name": "A" },
+            "chat": { "id": -100123, "title": "T", "type": "supergroup" },
+            "date": 1666666666,
+            "text": text,
+        });
+        if let Some(media_group_id) = media_group_id {
+            msg["media_group_id"] = media_group_id.into();
+            msg["photo"] = serde_json::json!([
+                { "file_id": "f", "file_unique_id": "u", "width": 1, "height": 1 }
+            ]);
+            msg["caption"] = msg.as_object_mut().unwrap().remove("text").unwrap();
+        }
+        serde_json::from_value(msg).unwrap()
+    }
+
+    fn item_ids(thread: &Thread) -> Vec<Vec<i32>> {
+        thread
+            .items
+            .iter()
+            .map(|item| item.iter().map(|msg| msg.id.0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn consecutive() {
+        let mut thread = Thread::new(vec![msg(1, 1, "a", None)], None);
+        assert!(thread.push(msg(2, 1, "b", Some("g"))));
+        assert!(thread.push(msg(3, 1, "c", Some("g"))));
+        assert!(thread.push(msg(4, 2, "/post", None)));
+        assert!(thread.push(msg(5, 1, "d", None)));
+        assert!(!thread.push(msg(6, 2, "e", None)));
+        assert_eq!(item_ids(&thread), [vec![1], vec![2, 3], vec![5]]);
+        assert!(thread.interrupted);
+
+        let mut thread = Thread::new(vec![msg(1, 1, "a", None)], Some(2));
+        assert!(thread.push(msg(2, 1, "b", Some("g"))));
+        assert!(thread.push(msg(3, 1, "c", Some("g"))));
+        assert!(!thread.push(msg(4, 2, "e", None)));
+        assert_eq!(item_ids(&thread), [vec![1], vec![2, 3]]);
+        assert!(!thread.interrupted);
+
+        // Replied to the second item of an album
+        let mut thread = Thread::new(
+            vec![msg(1, 1, "a", Some("g")), msg(2, 1, "b", Some("g"))],
+            None,
+        );
+        assert!(thread.push(msg(3, 1, "c", Some("g"))));
+        assert!(thread.push(msg(4, 1, "d", None)));
+        assert_eq!(item_ids(&thread), [vec![1, 2, 3], vec![4]]);
+    }
+}
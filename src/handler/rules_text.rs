@@ -0,0 +1,169 @@
+use spdlog::prelude::*;
+
+use crate::{
+    config,
+    handler::{Request, Response},
+    settings::{TextRule, UserSettings},
+    util::{text::*, text_rule},
+};
+
+const HELP: &str = r#"Usage: /rules_text [help | list | add | remove <number> | test]
+
+Rules replace or delete text of synced messages before posting, in order. Patterns are regular
+expressions (https://docs.rs/regex/#syntax), e.g. (?i) for case-insensitive, (?m) for ^ and $ to
+match at line breaks.
+
+Rules are per user, they apply to what you sync with /post and not to other users syncing from the
+same chat. Use +chat to limit one of your rules to a chat.
+
+  /rules_text add [+chat] <pattern>
+  <replacement>
+      add a rule, the replacement goes on the following lines, it deletes the matches if omitted.
+      $1 or ${name} refer to capture groups, $$ is a literal $. With +chat, the rule only applies
+      to messages of this chat.
+  /rules_text list : list your rules
+  /rules_text remove <number> : remove a rule
+  /rules_text test : reply to a message to preview its text transformed by your rules
+
+Examples:
+  /rules_text add [?&]utm_[^&\s]*
+  /rules_text add +chat (?m)^Subscribe to our channel.*$
+"#;
+
+pub async fn handle<'a>(
+    req: &Request,
+    arg: impl Into<String>,
+) -> Result<Response<'a>, Response<'a>> {
+    let arg = arg.into();
+    let (action, rest) = arg
+        .trim_start()
+        .split_once(char::is_whitespace)
+        .unwrap_or((arg.trim(), ""));
+    if action == "help" {
+        return Ok(Response::reply_to(mtb().pre(HELP).build()));
+    }
+
+    let chat = &req.msg().chat;
+    let user = req
+        .msg()
+        .from()
+        .ok_or_else(|| Response::reply_to("No user."))?;
+
+    let mut settings = UserSettings::load(req.state(), user.id)
+        .await
+        .map_err(|err| Response::reply_to(format!("Failed to load settings.\n\n{err}")))?;
+    let save = |settings: UserSettings| async move {
+        settings
+            .save(req.state(), user.id)
+            .await
+            .map_err(|err| Response::reply_to(format!("Failed to save settings.\n\n{err}")))
+    };
+
+    match action {
+        "" | "list" => Ok(Response::reply_to(list(&settings.text_rules, chat.id.0))),
+        "add" => {
+            if settings.text_rules.len() >= config::MAX_TEXT_RULES {
+                return Err(Response::reply_to(format!(
+                    "Too many rules, at most {} rules can be added.",
+                    config::MAX_TEXT_RULES
+                )));
+            }
+
+            let (first_line, replacement) = rest.split_once('\n').unwrap_or((rest, ""));
+            let first_line = first_line.trim_start();
+            let (pattern, chat_id) = match first_line.strip_prefix("+chat") {
+                Some(pattern) if pattern.starts_with(char::is_whitespace) => {
+                    (pattern.trim_start(), Some(chat.id.0))
+                }
+                _ => (first_line, None),
+            };
+            if pattern.is_empty() {
+                return Err(Response::reply_to(mtb().pre(HELP).build()));
+            }
+            text_rule::compile(pattern)
+                .map_err(|err| Response::reply_to(format!("Invalid pattern.\n\n{err}")))?;
+
+            settings.text_rules.push(TextRule {
+                pattern: pattern.to_owned(),
+                replacement: replacement.to_owned(),
+                chat_id,
+            });
+            let number = settings.text_rules.len();
+            save(settings).await?;
+            info!("user '{}' added a text rule", user.id);
+
+            Ok(Response::reply_to(format!("Rule {number} added.")))
+        }
+        "remove" => {
+            let number = rest
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|number| (1..=settings.text_rules.len()).contains(number))
+                .ok_or_else(|| {
+                    Response::reply_to(format!(
+                        "Failed to parse arguments.\n\n'{}' is not a number of your rules, see /rules_text list.",
+                        rest.trim()
+                    ))
+                })?;
+
+            settings.text_rules.remove(number - 1);
+            save(settings).await?;
+            info!("user '{}' removed a text rule", user.id);
+
+            Ok(Response::reply_to(format!("Rule {number} removed.")))
+        }
+        "test" => {
+            let Some(msg) = req.msg().reply_to_message() else {
+                return Err(Response::reply_to(
+                    "Reply to a message to preview its text transformed by your rules.",
+                ));
+            };
+            let (text, entities) = match msg.text() {
+                Some(text) => (text, msg.entities()),
+                None => (msg.caption().unwrap_or(""), msg.caption_entities()),
+            };
+
+            let mut msg_text = MessageText::new(text.to_owned(), entities.unwrap_or(&[]).to_vec());
+            if !text_rule::apply(&settings.text_rules, msg.chat.id, &mut msg_text) {
+                return Ok(Response::reply_to("None of your rules changed the text."));
+            }
+            if msg_text.text().trim().is_empty() {
+                return Ok(Response::reply_to(
+                    "Your rules removed all the text of the message.",
+                ));
+            }
+            Ok(Response::reply_to(msg_text))
+        }
+        _ => Err(Response::reply_to(mtb().pre(HELP).build())),
+    }
+}
+
+fn list(rules: &[TextRule], chat_id: i64) -> MessageText<'static> {
+    if rules.is_empty() {
+        return mtb()
+            .plain("You have no text rules.\n\nSend ")
+            .code("/rules_text help")
+            .plain(" for how to add them.")
+            .build();
+    }
+
+    let mut resp = mtb().bold("Your text rules\n");
+    for (i, rule) in rules.iter().enumerate() {
+        resp = resp.plain(format!("\n{}. ", i + 1)).code(&rule.pattern);
+        resp = if rule.replacement.is_empty() {
+            resp.plain(" → delete")
+        } else {
+            resp.plain(" → ").code(&rule.replacement)
+        };
+        resp = match rule.chat_id {
+            Some(id) if id == chat_id => resp.plain(" (only in this chat)"),
+            Some(id) => resp.plain(format!(" (only in chat {id})")),
+            None => resp,
+        };
+    }
+    resp.plain("\n\nSend ")
+        .code("/rules_text help")
+        .plain(" for how to change them.")
+        .build()
+}
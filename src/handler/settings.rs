@@ -0,0 +1,138 @@
+use crate::{
+    cmd::{define_cmd_args, Args},
+    handler::{Request, Response},
+    settings::UserSettings,
+    util::text::*,
+};
+
+pub async fn handle<'a>(
+    req: &Request,
+    arg: impl Into<String>,
+) -> Result<Response<'a>, Response<'a>> {
+    let args = SettingsArgs::parse(arg.into())
+        .map_err(|err| Response::reply_to(format!("Failed to parse arguments.\n\n{err}")))?;
+    if args.help {
+        return Ok(Response::reply_to(mtb().pre(SettingsArgs::help()).build()));
+    }
+
+    let user = req
+        .msg()
+        .from()
+        .ok_or_else(|| Response::reply_to("No user."))?;
+
+    let mut settings = UserSettings::load(req.state(), user.id)
+        .await
+        .map_err(|err| Response::reply_to(format!("Failed to load settings.\n\n{err}")))?;
+    let old_settings = settings.clone();
+
+    if let Some(partial) = args.partial {
+        settings.partial = partial;
+    }
+    if let Some(partial_note) = args.partial_note {
+        settings.partial_note = partial_note;
+    }
+
+    if let Some(strip_metadata) = args.strip_metadata {
+        settings.strip_metadata = strip_metadata;
+    }
+    if let Some(background_processing) = args.background_processing {
+        settings.background_processing = background_processing;
+    }
+    if let Some(link) = args.link {
+        settings.link = link;
+    }
+    if let Some(quote) = args.quote {
+        settings.quote = quote;
+    }
+    if let Some(mention_links) = args.mention_links {
+        settings.mention_links = mention_links;
+    }
+    if let Some(keep_tg_links) = args.keep_tg_links {
+        settings.keep_tg_links = keep_tg_links;
+    }
+
+    if settings != old_settings {
+        settings
+            .save(req.state(), user.id)
+            .await
+            .map_err(|err| Response::reply_to(format!("Failed to save settings.\n\n{err}")))?;
+    }
+
+    let on_off = |enable| if enable { "on" } else { "off" };
+
+    Ok(Response::reply_to(
+        mtb()
+            .bold("Your settings\n\n")
+            .plain(format!("partial: {}\n", on_off(settings.partial)))
+            .plain(format!("partial_note: {}\n", on_off(settings.partial_note)))
+            .plain(format!(
+                "strip_metadata: {}\n",
+                on_off(settings.strip_metadata)
+            ))
+            .plain(format!(
+                "background_processing: {}\n",
+                on_off(settings.background_processing)
+            ))
+            .plain(format!("link: {}\n", on_off(settings.link)))
+            .plain(format!("quote: {}\n", on_off(settings.quote)))
+            .plain(format!(
+                "mention_links: {}\n",
+                on_off(settings.mention_links)
+            ))
+            .plain(format!(
+                "keep_tg_links: {}\n",
+                on_off(settings.keep_tg_links)
+            ))
+            .plain(format!(
+                "template: {} (see /template)\n",
+                if settings.template.is_some() {
+                    "custom"
+                } else {
+                    "default"
+                }
+            ))
+            .plain(format!(
+                "text_rules: {} (see /rules_text)\n",
+                settings.text_rules.len()
+            ))
+            .plain("\nSend ")
+            .code("/settings help")
+            .plain(" for how to change them.")
+            .build(),
+    ))
+}
+
+define_cmd_args! {
+
+r#"Usage: /settings [option]*
+
+Options:
+  help  : show this help message
+  +/-partial : skip unsupported media instead of refusing the whole post (default: off)
+  +/-partial_note : mention omitted media in the synced status as well (default: off)
+  +/-strip_metadata : remove location and device metadata from images before uploading (default: on)
+  +/-background_processing : if the instance is still processing the media after the timeout, keep
+                             waiting in the background and post the status once it's done (default: off)
+  +/-link : link to the original Telegram message in the source, if it's public (default: off)
+  +/-quote : quote the message replied to, or link to its status if it was synced (default: off)
+  +/-mention_links : turn mentions of users without a known fediverse handle into t.me links,
+                     instead of plain names (default: off, see /fedi_handle)
+  +/-keep_tg_links : links to Telegram messages synced earlier are replaced with links to their
+                     statuses, keep the Telegram links and add the status links next to them (default: off)
+
+Options of /post with the same name override these settings for a single post.
+"#
+
+    #[derive(PartialEq, Eq, Debug, Default)]
+    pub struct SettingsArgs {
+        pub help: bool,
+        pub partial: Option<bool>,
+        pub partial_note: Option<bool>,
+        pub strip_metadata: Option<bool>,
+        pub background_processing: Option<bool>,
+        pub link: Option<bool>,
+        pub quote: Option<bool>,
+        pub mention_links: Option<bool>,
+        pub keep_tg_links: Option<bool>,
+    }
+}
@@ -0,0 +1,123 @@
+use spdlog::prelude::*;
+use teloxide::types::MessageEntityKind;
+
+use crate::{
+    handler::{Request, Response},
+    settings::UserSettings,
+    util::{
+        template::{self, Template},
+        text::*,
+    },
+};
+
+const USAGE: &str = r#"Usage: /template [help | reset | <template>]
+
+Sets the layout of statuses synced by /post, instead of the default "text, then the source".
+The template is per user, it applies to what you sync in any chat and not to other users.
+
+Placeholders:
+"#;
+
+const SYNTAX: &str = r#"
+Sections:
+  {#var}...{/var} : only rendered if the value is not empty
+  {^var}...{/var} : only rendered if the value is empty
+  {{ and }} : literal braces
+
+Example:
+  📢 {text}{#hashtags}
+
+  {hashtags}{/hashtags}{#source_name}
+  via {source_name}{#source_link} {source_link}{/source_link}{/source_name}
+
+With a template, +/-src still decides whether there is a source, and +link is not needed.
+"#;
+
+pub async fn handle<'a>(
+    req: &Request,
+    arg: impl Into<String>,
+) -> Result<Response<'a>, Response<'a>> {
+    let arg = arg.into();
+    let arg = arg.trim();
+    if arg == "help" {
+        return Ok(Response::reply_to(mtb().pre(help()).build()));
+    }
+
+    let user = req
+        .msg()
+        .from()
+        .ok_or_else(|| Response::reply_to("No user."))?;
+
+    let mut settings = UserSettings::load(req.state(), user.id)
+        .await
+        .map_err(|err| Response::reply_to(format!("Failed to load settings.\n\n{err}")))?;
+
+    match arg {
+        "" => Ok(Response::reply_to(match &settings.template {
+            Some(template) => mtb()
+                .bold("Your template\n\n")
+                .pre(template.clone())
+                .plain("\nSend ")
+                .code("/template help")
+                .plain(" for how to change it.")
+                .build(),
+            None => mtb()
+                .plain("You are using the default layout.\n\nSend ")
+                .code("/template help")
+                .plain(" for how to change it.")
+                .build(),
+        })),
+        "reset" => {
+            settings.template = None;
+            settings
+                .save(req.state(), user.id)
+                .await
+                .map_err(|err| Response::reply_to(format!("Failed to save settings.\n\n{err}")))?;
+            info!("user '{}' reset the template", user.id);
+
+            Ok(Response::reply_to(
+                "Template reset, statuses use the default layout.",
+            ))
+        }
+        _ => {
+            let template = arg
+                .parse::<Template>()
+                .map_err(|err| Response::reply_to(format!("Invalid template.\n\n{err}")))?;
+
+            settings.template = Some(arg.to_owned());
+            settings
+                .save(req.state(), user.id)
+                .await
+                .map_err(|err| Response::reply_to(format!("Failed to save settings.\n\n{err}")))?;
+            info!("user '{}' set a template", user.id);
+
+            let mut resp = MessageText::from("Template saved, preview:\n\n");
+            resp.append(template.render(&preview_values()));
+            Ok(Response::reply_to(resp))
+        }
+    }
+}
+
+fn help() -> String {
+    let mut help = USAGE.to_owned();
+    for (name, description) in template::VARS {
+        help.push_str(&format!("  {{{name}}} : {description}\n"));
+    }
+    help.push_str(SYNTAX);
+    help
+}
+
+fn preview_values() -> template::Values<'static> {
+    let mut text = MessageText::from("Hello ");
+    text.append_text_with_entity("#world", MessageEntityKind::Hashtag);
+
+    template::Values {
+        text,
+        source_name: Some("Example Channel".into()),
+        source_username: Some("example".into()),
+        source_link: "https://t.me/example/1".parse().ok(),
+        tg_link: "https://t.me/example/1".parse().ok(),
+        hashtags: vec!["#news".into()],
+        date: "2023-01-31".into(),
+    }
+}
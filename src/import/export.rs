@@ -0,0 +1,278 @@
+use serde::Deserialize;
+use serde_json as json;
+use teloxide::types::{ChatId, MessageEntityKind, StickerFormat};
+
+use crate::{config, util::text::MessageText};
+
+// The JSON format of "Export chat history" of Telegram Desktop. Only the fields
+// needed for syncing are parsed, media paths are relative to the export
+// directory.
+
+#[derive(Deserialize)]
+pub struct Export {
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    // Without the `-100` prefix of the Bot API for channels and supergroups
+    pub id: Option<i64>,
+    pub messages: Vec<ExportMessage>,
+}
+
+impl Export {
+    pub fn is_channel(&self) -> bool {
+        matches!(self.kind.as_str(), "public_channel" | "private_channel")
+    }
+
+    // The id of the chat in the Bot API, which the settings and records of
+    // chats are keyed by
+    pub fn chat_id(&self) -> Option<ChatId> {
+        let id = self.id?;
+        Some(ChatId(match self.kind.as_str() {
+            "public_channel" | "private_channel" | "public_supergroup" | "private_supergroup" => {
+                -1_000_000_000_000 - id
+            }
+            "private_group" => -id,
+            _ => id,
+        }))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportMessage {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    // Local time of the exporting machine, e.g. "2021-01-01T10:00:00"
+    pub date: String,
+    pub date_unixtime: Option<String>,
+    pub from: Option<String>,
+    pub from_id: Option<String>,
+    pub forwarded_from: Option<String>,
+    pub reply_to_message_id: Option<i64>,
+    #[serde(default)]
+    pub text_entities: Vec<TextEntity>,
+    pub photo: Option<String>,
+    pub file: Option<String>,
+    pub thumbnail: Option<String>,
+    pub media_type: Option<String>,
+    pub mime_type: Option<String>,
+    pub poll: Option<json::Value>,
+    pub location_information: Option<json::Value>,
+    pub contact_information: Option<json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct TextEntity {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub text: String,
+    pub href: Option<String>,
+    pub language: Option<String>,
+}
+
+pub struct ExportMedia<'a> {
+    // `None` if the file was not included in the export
+    pub path: Option<&'a str>,
+    pub thumbnail: Option<&'a str>,
+    pub mime_type: String,
+    pub is_photo: bool,
+    pub is_sticker: bool,
+}
+
+impl ExportMedia<'_> {
+    pub fn kind_name(&self) -> &'static str {
+        match (self.is_photo, self.is_sticker) {
+            (true, _) => "photo",
+            (_, true) => "sticker",
+            _ => "file",
+        }
+    }
+
+    // Stickers are exported in their original format
+    pub fn sticker_format(&self) -> StickerFormat {
+        match self.mime_type.as_str() {
+            "application/x-tgsticker" => StickerFormat::Animated,
+            "video/webm" => StickerFormat::Video,
+            _ => StickerFormat::Raster,
+        }
+    }
+}
+
+impl ExportMessage {
+    pub fn is_message(&self) -> bool {
+        self.kind == "message"
+    }
+
+    pub fn has_text(&self) -> bool {
+        self.text_entities
+            .iter()
+            .any(|entity| !entity.text.is_empty())
+    }
+
+    pub fn text(&self) -> MessageText<'static> {
+        let mut msg_text = MessageText::new(String::new(), vec![]);
+        for entity in &self.text_entities {
+            match entity_kind(entity) {
+                Some(kind) => msg_text.append_text_with_entity(&entity.text, kind),
+                None => msg_text.append_text(&entity.text),
+            }
+        }
+        msg_text
+    }
+
+    pub fn media(&self) -> Option<ExportMedia<'_>> {
+        // Files skipped by the export settings are replaced with a note like
+        // "(File not included. Change data exporting settings to download.)"
+        fn path(path: &str) -> Option<&str> {
+            Some(path).filter(|path| !path.starts_with('('))
+        }
+
+        if let Some(photo) = &self.photo {
+            return Some(ExportMedia {
+                path: path(photo),
+                thumbnail: None,
+                mime_type: "image/jpeg".into(),
+                is_photo: true,
+                is_sticker: false,
+            });
+        }
+
+        let file = self.file.as_deref()?;
+        Some(ExportMedia {
+            path: path(file),
+            thumbnail: self.thumbnail.as_deref().and_then(path),
+            mime_type: self.mime_type.clone().unwrap_or_else(|| {
+                mime_guess::from_path(file)
+                    .first_or_octet_stream()
+                    .to_string()
+            }),
+            is_photo: false,
+            is_sticker: self.media_type.as_deref() == Some("sticker"),
+        })
+    }
+
+    pub fn unsupported_kind(&self) -> Option<&'static str> {
+        if self.poll.is_some() {
+            Some("poll")
+        } else if self.location_information.is_some() {
+            Some("location")
+        } else if self.contact_information.is_some() {
+            Some("contact")
+        } else {
+            None
+        }
+    }
+}
+
+// Each item is a message or all messages of an album. The export has no album
+// ids, so consecutive media messages of the same sender sent at the same second
+// are taken as an album, at most one of them has a caption. Service messages
+// are skipped.
+pub fn group_items(messages: &[ExportMessage]) -> Vec<Vec<&ExportMessage>> {
+    let mut items: Vec<Vec<&ExportMessage>> = vec![];
+
+    for msg in messages.iter().filter(|msg| msg.is_message()) {
+        let same_album = items.last().is_some_and(|item| {
+            let last = item[item.len() - 1];
+            msg.media().is_some()
+                && last.media().is_some()
+                && msg.from_id == last.from_id
+                && msg.date_unixtime.as_ref().or(Some(&msg.date))
+                    == last.date_unixtime.as_ref().or(Some(&last.date))
+                && !(msg.has_text() && item.iter().any(|m| m.has_text()))
+                && item.len() < config::MEDIA_GROUP_MAX_SIZE
+        });
+
+        match items.last_mut().filter(|_| same_album) {
+            Some(item) => item.push(msg),
+            None => items.push(vec![msg]),
+        }
+    }
+    items
+}
+
+fn entity_kind(entity: &TextEntity) -> Option<MessageEntityKind> {
+    use MessageEntityKind::*;
+
+    Some(match entity.kind.as_str() {
+        "bold" => Bold,
+        "italic" => Italic,
+        "underline" => Underline,
+        "strikethrough" => Strikethrough,
+        "spoiler" => Spoiler,
+        "code" => Code,
+        "pre" => Pre {
+            language: entity.language.clone().filter(|lang| !lang.is_empty()),
+        },
+        "text_link" => TextLink {
+            url: entity.href.as_deref()?.parse().ok()?,
+        },
+        "link" => Url,
+        "mention" => Mention,
+        "hashtag" => Hashtag,
+        "cashtag" => Cashtag,
+        "bot_command" => BotCommand,
+        "email" => Email,
+        "phone" => PhoneNumber,
+        // "plain", "mention_name", "custom_emoji", "blockquote", ...
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_export() {
+        let export: Export = json::from_str(
+            r#"{
+  "name": "Channel",
+  "type": "public_channel",
+  "id": 123,
+  "messages": [
+    {"id": 1, "type": "service", "date": "2021-01-01T09:00:00", "date_unixtime": "1609491600", "actor": "Channel", "actor_id": "channel123", "action": "create_channel", "title": "Channel", "text": "", "text_entities": []},
+    {"id": 2, "type": "message", "date": "2021-01-01T10:00:00", "date_unixtime": "1609495200", "from": "Channel", "from_id": "channel123", "photo": "photos/photo_1.jpg", "width": 1280, "height": 720, "text": "", "text_entities": [{"type": "plain", "text": "🐱 meow "}, {"type": "text_link", "text": "link", "href": "https://example.com/"}]},
+    {"id": 3, "type": "message", "date": "2021-01-01T10:00:00", "date_unixtime": "1609495200", "from": "Channel", "from_id": "channel123", "file": "(File not included. Change data exporting settings to download.)", "media_type": "video_file", "mime_type": "video/mp4", "text": "", "text_entities": []},
+    {"id": 4, "type": "message", "date": "2021-01-01T10:00:00", "date_unixtime": "1609495200", "from": "Channel", "from_id": "channel123", "photo": "photos/photo_2.jpg", "text": "", "text_entities": [{"type": "bold", "text": "another caption"}]},
+    {"id": 5, "type": "message", "date": "2021-01-01T10:05:00", "date_unixtime": "1609495500", "from": "Channel", "from_id": "channel123", "forwarded_from": "Someone", "reply_to_message_id": 2, "text": "", "text_entities": [{"type": "plain", "text": "text only"}]},
+    {"id": 6, "type": "message", "date": "2021-01-01T10:06:00", "date_unixtime": "1609495560", "from": "Channel", "from_id": "channel123", "file": "stickers/sticker.tgs", "thumbnail": "stickers/sticker.tgs_thumb.jpg", "media_type": "sticker", "sticker_emoji": "🐱", "mime_type": "application/x-tgsticker", "text": "", "text_entities": []}
+  ]
+}"#,
+        )
+        .unwrap();
+
+        assert!(export.is_channel());
+        assert_eq!(export.chat_id(), Some(ChatId(-1000000000123)));
+
+        let items = group_items(&export.messages);
+        assert_eq!(
+            items
+                .iter()
+                .map(|item| item.iter().map(|msg| msg.id).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec![2, 3], vec![4], vec![5], vec![6]]
+        );
+
+        let msg_text = items[0][0].text();
+        assert_eq!(msg_text.text(), "🐱 meow link");
+        assert_eq!(msg_text.entities().len(), 1);
+        assert_eq!(msg_text.entities()[0].offset, 8);
+        assert_eq!(msg_text.entities()[0].length, 4);
+
+        let media = items[0][1].media().unwrap();
+        assert_eq!(media.path, None);
+        assert_eq!(media.mime_type, "video/mp4");
+        assert_eq!(
+            items[0][0].media().unwrap().path,
+            Some("photos/photo_1.jpg")
+        );
+        assert!(items[2][0].media().is_none());
+        assert_eq!(items[2][0].reply_to_message_id, Some(2));
+
+        let sticker = items[3][0].media().unwrap();
+        assert!(sticker.is_sticker);
+        assert_eq!(sticker.sticker_format(), StickerFormat::Animated);
+        assert_eq!(sticker.thumbnail, Some("stickers/sticker.tgs_thumb.jpg"));
+    }
+}
@@ -0,0 +1,651 @@
+mod export;
+
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail};
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use spdlog::prelude::*;
+use teloxide::types::{ChatId, Message, MessageId, StickerFormat, UserId};
+use tokio::{fs, time};
+
+use self::export::{Export, ExportMedia, ExportMessage};
+use crate::{
+    cmd::parse_duration,
+    config,
+    handler::post::{
+        convert_sticker, detect_lang, fit_image, format_text_for_mastodon, instance_config,
+        is_still_image_type, lay_out, Origin, Source,
+    },
+    mastodon::{
+        self, AttachedMedia, AttachmentId, InstanceConfig, Language as MLanguage, LoginUser,
+        StatusBuilder, Visibility,
+    },
+    settings::UserSettings,
+    util::{sync_record, text::MessageText, text_rule},
+    InstanceState,
+};
+
+// Replays a Telegram Desktop export (result.json and its media folders) to
+// Mastodon, converted the same way as `/post`.
+
+pub const USAGE: &str = r#"Usage: tgbot-mastodon-sync import <export directory or result.json> --tg-user <id> [option]*
+
+Options:
+  --tg-user <id> : the Telegram user id whose linked Mastodon account posts the statuses
+  --chat-username <username> : the username of the exported chat if it's public, for links
+                               to its messages
+  --dry-run : only print what would be posted, the database is still required
+  --interval <duration> : delay between statuses, e.g. 1m (default: 30s)
+  --backdate : append the original date of messages to the statuses
+  --src / --no-src : force enable / disable appending the source of messages
+                     (default: auto, forwarded messages and messages of other users)
+  --checkpoint <path> : file recording posted messages, to resume an interrupted import
+                        (default: tgbot-mastodon-sync-import.json in the export directory)
+"#;
+
+pub struct ImportOptions {
+    pub path: PathBuf,
+    pub tg_user_id: UserId,
+    pub chat_username: Option<String>,
+    pub dry_run: bool,
+    pub interval: Duration,
+    pub backdate: bool,
+    pub src: Option<bool>,
+    pub checkpoint: Option<PathBuf>,
+}
+
+impl ImportOptions {
+    pub fn parse(args: impl IntoIterator<Item = impl Into<String>>) -> anyhow::Result<Self> {
+        let mut args = args.into_iter().map(Into::into);
+        let (mut path, mut tg_user_id, mut chat_username, mut checkpoint) =
+            (None, None, None, None);
+        let (mut dry_run, mut backdate, mut src) = (false, false, None);
+        let mut interval = config::DEFAULT_IMPORT_INTERVAL;
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("option '{arg}' requires a value"))
+            };
+
+            match arg.as_str() {
+                "--tg-user" => {
+                    let value = value()?;
+                    tg_user_id = Some(UserId(
+                        value
+                            .parse()
+                            .map_err(|_| anyhow!("invalid user id '{value}'"))?,
+                    ));
+                }
+                "--chat-username" => {
+                    chat_username = Some(value()?.trim_start_matches('@').to_owned())
+                }
+                "--dry-run" => dry_run = true,
+                "--interval" => interval = parse_duration(value()?)?,
+                "--backdate" => backdate = true,
+                "--src" => src = Some(true),
+                "--no-src" => src = Some(false),
+                "--checkpoint" => checkpoint = Some(PathBuf::from(value()?)),
+                _ if arg.starts_with("--") => bail!("unknown option '{arg}'"),
+                _ if path.is_some() => bail!("unexpected argument '{arg}'"),
+                _ => path = Some(PathBuf::from(arg)),
+            }
+        }
+
+        Ok(Self {
+            path: path.ok_or_else(|| anyhow!("no export path specified"))?,
+            tg_user_id: tg_user_id.ok_or_else(|| anyhow!("no --tg-user specified"))?,
+            chat_username,
+            dry_run,
+            interval,
+            backdate,
+            src,
+            checkpoint,
+        })
+    }
+}
+
+// Telegram message ids of the export to ids of the posted statuses
+#[derive(Default, Serialize, Deserialize)]
+struct Checkpoint {
+    posted: BTreeMap<i64, String>,
+}
+
+impl Checkpoint {
+    async fn load(path: &Path) -> anyhow::Result<Self> {
+        match fs::read(path).await {
+            Ok(data) => Ok(json::from_slice(&data)?),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Written to a temporary file first, so that an interruption doesn't
+    // leave a broken checkpoint
+    async fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, json::to_vec_pretty(self)?).await?;
+        fs::rename(&tmp_path, path).await?;
+        Ok(())
+    }
+}
+
+struct Context<'a> {
+    inst_state: &'a InstanceState,
+    login_user: &'a LoginUser,
+    settings: &'a UserSettings,
+    config: &'a InstanceConfig,
+    export: &'a Export,
+    export_dir: &'a Path,
+    options: &'a ImportOptions,
+}
+
+impl Context<'_> {
+    // `ChatId(0)` if the export has no id, which only matches the rules and
+    // mappings that apply to all chats
+    fn chat_id(&self) -> ChatId {
+        self.export.chat_id().unwrap_or(ChatId(0))
+    }
+
+    fn message_url(&self, msg_id: i64) -> Option<reqwest::Url> {
+        Message::url_of(
+            self.export.chat_id()?,
+            self.options.chat_username.as_deref(),
+            MessageId(msg_id as i32),
+        )
+    }
+}
+
+struct Draft<'a> {
+    msg_text: MessageText<'static>,
+    lang: Option<MLanguage>,
+    with_src: bool,
+    // Only the media to upload, the omitted ones are in `warnings`
+    media: Vec<ExportMedia<'a>>,
+    warnings: Vec<String>,
+}
+
+pub async fn run(db_url: String, options: ImportOptions) -> anyhow::Result<()> {
+    let (export_path, export_dir) = if options.path.is_dir() {
+        (options.path.join("result.json"), options.path.clone())
+    } else {
+        let dir = options.path.parent().unwrap_or(Path::new(".")).to_owned();
+        (options.path.clone(), dir)
+    };
+
+    let export: Export = json::from_slice(
+        &fs::read(&export_path)
+            .await
+            .map_err(|err| anyhow!("failed to read '{}': {err}", export_path.display()))?,
+    )
+    .map_err(|err| anyhow!("failed to parse '{}': {err}", export_path.display()))?;
+
+    let checkpoint_path = options
+        .checkpoint
+        .clone()
+        .unwrap_or_else(|| export_dir.join(config::IMPORT_CHECKPOINT_FILE_NAME));
+    let mut checkpoint = Checkpoint::load(&checkpoint_path).await.map_err(|err| {
+        anyhow!(
+            "failed to load checkpoint '{}': {err}",
+            checkpoint_path.display()
+        )
+    })?;
+
+    let items = export::group_items(&export.messages);
+    info!(
+        "importing {} item(s) of '{}', {} already posted",
+        items.len(),
+        export.name.as_deref().unwrap_or("Untitled"),
+        items
+            .iter()
+            .filter(|item| checkpoint.posted.contains_key(&item[0].id))
+            .count()
+    );
+    if export.chat_id().is_none() {
+        warn!("the export has no chat id, only the settings for all chats apply");
+    }
+
+    // Also needed by `--dry-run`, statuses are composed with the settings of
+    // the user
+    let inst_state = InstanceState::new(db_url).await?;
+    let login_user = mastodon::Client::new(Arc::clone(&inst_state))
+        .login(options.tg_user_id)
+        .await
+        .map_err(|err| anyhow!("user '{}' is not logged in: {err}", options.tg_user_id))?;
+    let settings = UserSettings::load(&inst_state, options.tg_user_id)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("failed to load settings, fallback to defaults: {err}");
+            Default::default()
+        });
+    let config = instance_config(&login_user).await;
+
+    let ctx = Context {
+        inst_state: &inst_state,
+        login_user: &login_user,
+        settings: &settings,
+        config: &config,
+        export: &export,
+        export_dir: &export_dir,
+        options: &options,
+    };
+
+    if options.dry_run {
+        report(&ctx, &items, &checkpoint).await;
+        return Ok(());
+    }
+
+    let mut posted_count = 0;
+    for (i, item) in items.iter().enumerate() {
+        let first = item[0];
+        if checkpoint.posted.contains_key(&first.id) {
+            continue;
+        }
+        let progress = format!("{}/{}", i + 1, items.len());
+
+        let mut draft = match draft(&ctx, item).await {
+            Ok(draft) => draft,
+            Err(reason) => {
+                warn!("({progress}) skipped message '{}': {reason}", first.id);
+                continue;
+            }
+        };
+
+        if posted_count > 0 {
+            time::sleep(options.interval).await;
+        }
+
+        let mut media_ids = vec![];
+        for media in &draft.media {
+            let Some(path) = media.path else {
+                continue;
+            };
+
+            let data = match read_media(&ctx, path, media).await {
+                Ok(data) => data,
+                Err(err) => {
+                    draft.warnings.push(format!(
+                        "failed to convert {} '{path}', omitted: {err}",
+                        media.kind_name()
+                    ));
+                    continue;
+                }
+            };
+            match upload(&login_user, data, settings.strip_metadata).await {
+                Ok(id) => media_ids.push(id),
+                Err(err) => {
+                    delete_uploaded(&login_user, &media_ids).await;
+                    bail!("failed to upload '{path}' of message '{}': {err}", first.id);
+                }
+            }
+        }
+
+        if media_ids.is_empty() && draft.msg_text.text().trim().is_empty() {
+            warn!(
+                "({progress}) skipped message '{}': nothing left to sync",
+                first.id
+            );
+            continue;
+        }
+
+        let mut status = StatusBuilder::new();
+        status.visibility(Visibility::Public);
+        if let Some(in_reply_to) = first
+            .reply_to_message_id
+            .and_then(|id| checkpoint.posted.get(&id))
+        {
+            status.in_reply_to(in_reply_to);
+        }
+        if let Some(lang) = draft.lang {
+            status.language(lang);
+        }
+        if !media_ids.is_empty() {
+            status.media_ids(media_ids.clone());
+        }
+
+        let (text, is_formatted) = format_text_for_mastodon(&draft.msg_text);
+        status.status(text);
+        if is_formatted {
+            status.content_type("text/markdown");
+        }
+
+        let posted = match status.build() {
+            Ok(status) => login_user.post_status(status, None).await,
+            Err(err) => Err(err.into()),
+        };
+        let posted = match posted {
+            Ok(posted) => posted,
+            Err(err) => {
+                delete_uploaded(&login_user, &media_ids).await;
+                bail!("failed to post message '{}': {err}", first.id);
+            }
+        };
+
+        info!(
+            "({progress}) imported message '{}' ({:?}, {}): {}",
+            first.id,
+            draft.lang,
+            if draft.with_src { "w/ src" } else { "w/o src" },
+            posted.url
+        );
+        for warning in draft.warnings {
+            warn!("({progress}) message '{}': {warning}", first.id);
+        }
+
+        if let Some(chat_id) = export.chat_id() {
+            sync_record::record_ids(
+                &inst_state,
+                &login_user,
+                chat_id,
+                options.chat_username.as_deref(),
+                item.iter().map(|msg| MessageId(msg.id as i32)),
+                &posted,
+            )
+            .await;
+        }
+
+        checkpoint.posted.insert(first.id, posted.id);
+        checkpoint.save(&checkpoint_path).await.map_err(|err| {
+            anyhow!(
+                "failed to save checkpoint '{}': {err}",
+                checkpoint_path.display()
+            )
+        })?;
+        posted_count += 1;
+    }
+
+    info!("import finished, {posted_count} status(es) posted");
+    Ok(())
+}
+
+// Composes the status the way `/post` does, with the exporting user as the
+// trigger
+async fn draft<'a>(ctx: &Context<'_>, item: &[&'a ExportMessage]) -> Result<Draft<'a>, String> {
+    let first = item[0];
+    let mut warnings = vec![];
+
+    if let Some(kind) = first.unsupported_kind() {
+        return Err(format!("{kind} is not supported"));
+    }
+
+    let mut msg_text = item
+        .iter()
+        .find(|msg| msg.has_text())
+        .map(|msg| msg.text())
+        .unwrap_or_else(|| MessageText::new(String::new(), vec![]));
+    text_rule::apply(&ctx.settings.text_rules, ctx.chat_id(), &mut msg_text);
+
+    let mut omitted = vec![];
+    let media = item
+        .iter()
+        .filter_map(|msg| msg.media())
+        .filter(|media| match omit_reason(ctx, media) {
+            Some(reason) => {
+                omitted.push(media.kind_name());
+                warnings.push(format!("{reason}, omitted"));
+                false
+            }
+            None => true,
+        })
+        .collect::<Vec<_>>();
+
+    if media.is_empty() && msg_text.text().trim().is_empty() {
+        return Err(if omitted.is_empty() {
+            "nothing to sync".into()
+        } else {
+            format!(
+                "nothing left to sync after omitting media: {}",
+                warnings.join("; ")
+            )
+        });
+    }
+
+    if !omitted.is_empty() && ctx.settings.partial_note {
+        let count = omitted.len();
+        omitted.sort_unstable();
+        omitted.dedup();
+
+        if !msg_text.text().is_empty() {
+            msg_text.append_text("\n\n");
+        }
+        msg_text.append_text(format!(
+            "({count} item(s) not synced: {})",
+            omitted.join(", ")
+        ));
+    }
+
+    let lang = detect_lang(&msg_text);
+
+    if ctx.options.backdate {
+        if !msg_text.text().is_empty() {
+            msg_text.append_text("\n\n");
+        }
+        // "2021-01-01T10:00:00" -> "2021-01-01 10:00"
+        let date = first.date.replacen('T', " ", 1);
+        msg_text.append_text(format!(
+            "(originally posted on {})",
+            date.get(..16).unwrap_or(&date)
+        ));
+    }
+
+    let with_src = lay_out(
+        ctx.inst_state,
+        ctx.login_user,
+        ctx.settings,
+        ctx.settings.link,
+        Origin {
+            chat_id: ctx.chat_id(),
+            source: source(ctx, first),
+            tg_link: ctx.message_url(first.id),
+            date: first.date.get(..10).unwrap_or(&first.date).into(),
+        },
+        &mut msg_text,
+    )
+    .await;
+
+    Ok(Draft {
+        msg_text,
+        lang,
+        with_src,
+        media,
+        warnings,
+    })
+}
+
+fn omit_reason(ctx: &Context<'_>, media: &ExportMedia<'_>) -> Option<String> {
+    let Some(path) = media.path else {
+        return Some(format!(
+            "{} was not included in the export",
+            media.kind_name()
+        ));
+    };
+
+    if media.is_sticker {
+        // The export has no thumbnails of some old stickers
+        (media.sticker_format() == StickerFormat::Animated && media.thumbnail.is_none())
+            .then(|| format!("animated sticker '{path}' has no static thumbnail in the export"))
+    } else if !media.is_photo && !ctx.config.media_attachments.is_supported(&media.mime_type) {
+        Some(format!(
+            "{} '{path}' has unsupported type '{}'",
+            media.kind_name(),
+            media.mime_type
+        ))
+    } else {
+        None
+    }
+}
+
+// Follows the rules of `/post`. The export has only the names of senders, and
+// no links to forwarded posts.
+fn source(ctx: &Context<'_>, msg: &ExportMessage) -> Source {
+    let self_id = format!("user{}", ctx.options.tg_user_id);
+
+    let name = match ctx.options.src {
+        Some(false) => return Source::default(),
+        _ if msg.forwarded_from.is_some() => msg.forwarded_from.clone(),
+        Some(true) => msg.from.clone(),
+        None if ctx.export.is_channel() || msg.from_id.as_deref() == Some(&self_id) => None,
+        None => msg.from.clone(),
+    };
+
+    Source {
+        name,
+        username: None,
+        url: ctx
+            .options
+            .chat_username
+            .as_ref()
+            .and_then(|_| ctx.message_url(msg.id)),
+    }
+}
+
+enum MediaData {
+    Converted(Vec<u8>),
+    File(fs::File, u64),
+}
+
+// Stickers and still images are converted like `/post` does, other files are
+// uploaded as they are
+async fn read_media(
+    ctx: &Context<'_>,
+    path: &str,
+    media: &ExportMedia<'_>,
+) -> anyhow::Result<MediaData> {
+    async fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+        Ok(fs::read(path).await?)
+    }
+
+    let path = ctx.export_dir.join(path);
+
+    if media.is_sticker {
+        let thumbnail = media.thumbnail.map(|thumb| ctx.export_dir.join(thumb));
+        let data = convert_sticker(
+            &media.sticker_format(),
+            || read(&path),
+            thumbnail.as_deref().map(|thumb| move || read(thumb)),
+        )
+        .await?;
+        Ok(MediaData::Converted(data))
+    } else if media.is_photo || is_still_image_type(&media.mime_type) {
+        let data = fit_image(read(&path).await?, &ctx.config.media_attachments).await?;
+        Ok(MediaData::Converted(data))
+    } else {
+        let file = fs::File::open(&path).await?;
+        let len = file.metadata().await?.len();
+        Ok(MediaData::File(file, len))
+    }
+}
+
+async fn upload(
+    login_user: &LoginUser,
+    data: MediaData,
+    strip_metadata: bool,
+) -> anyhow::Result<AttachmentId> {
+    let _permit = login_user.upload_permit().await;
+
+    let attached = match data {
+        MediaData::Converted(data) => {
+            let len = data.len() as u64;
+            login_user
+                .attach_media(
+                    Cursor::new(data),
+                    Some(len),
+                    None,
+                    None,
+                    strip_metadata,
+                    |_| {},
+                )
+                .await?
+        }
+        MediaData::File(file, len) => {
+            login_user
+                .attach_media(file, Some(len), None, None, strip_metadata, |_| {})
+                .await?
+        }
+    };
+
+    match attached {
+        AttachedMedia::Processed(attachment) => Ok(attachment.id),
+        AttachedMedia::Processing(id) => {
+            login_user
+                .wait_for_media(
+                    &id,
+                    config::WAITING_FOR_SERVER_PROCESS_MEDIA_BACKGROUND_TIMEOUT,
+                )
+                .await?;
+            Ok(id)
+        }
+    }
+}
+
+async fn delete_uploaded(login_user: &LoginUser, ids: &[AttachmentId]) {
+    for id in ids {
+        if let Err(err) = login_user.delete_media(id).await {
+            warn!("failed to delete uploaded media '{id}': {err}");
+        }
+    }
+}
+
+// Written to stdout rather than logged, since the report is the output of
+// `--dry-run`, meant to be read or saved as a whole
+async fn report(ctx: &Context<'_>, items: &[Vec<&ExportMessage>], checkpoint: &Checkpoint) {
+    let (mut to_post, mut skipped) = (0, 0);
+
+    for item in items {
+        let first = item[0];
+        if checkpoint.posted.contains_key(&first.id) {
+            continue;
+        }
+
+        match draft(ctx, item).await {
+            Ok(draft) => {
+                to_post += 1;
+
+                let mut line = format!("#{} ", first.id);
+                if item.len() > 1 {
+                    line.push_str(&format!("(album of {}) ", item.len()));
+                }
+                line.push_str(&format!(
+                    "[{}, {}",
+                    draft.lang.and_then(|lang| lang.to_639_1()).unwrap_or("??"),
+                    if draft.with_src { "w/ src" } else { "w/o src" }
+                ));
+                if !draft.media.is_empty() {
+                    line.push_str(&format!(", {} media", draft.media.len()));
+                }
+                if let Some(reply_to) = first.reply_to_message_id {
+                    line.push_str(&format!(", reply to #{reply_to}"));
+                }
+                line.push_str("] ");
+
+                let text = draft.msg_text.text().replace('\n', " ");
+                let preview = text.chars().take(60).collect::<String>();
+                line.push_str(&preview);
+                if preview.len() < text.len() {
+                    line.push('…');
+                }
+
+                println!("{line}");
+                for warning in draft.warnings {
+                    println!("    ⚠️ {warning}");
+                }
+            }
+            Err(reason) => {
+                skipped += 1;
+                println!("#{} skipped: {reason}", first.id);
+            }
+        }
+    }
+
+    println!(
+        "\n{to_post} status(es) would be posted, {skipped} item(s) skipped, {} already posted",
+        items.len() - to_post - skipped
+    );
+}
@@ -1,20 +1,25 @@
+mod cache;
 mod cmd;
 pub mod config;
 mod db;
 mod handler;
+mod import;
 mod mastodon;
+mod settings;
 mod util;
 
 use std::sync::Arc;
 
+use anyhow::anyhow;
 use cmd::Command;
 use spdlog::prelude::*;
 use teloxide::{
     prelude::*,
-    types::{Me, Update},
+    types::{ChatMemberUpdated, Me, Update},
     utils::command::BotCommands,
 };
 
+pub use crate::import::{ImportOptions, USAGE as IMPORT_USAGE};
 use crate::util::handle;
 
 pub struct InstanceState {
@@ -29,9 +34,22 @@ impl InstanceState {
     }
 }
 
-pub async fn run(bot_token: impl Into<String>, db_url: impl AsRef<str>) -> anyhow::Result<()> {
-    let bot = Bot::new(bot_token);
+pub async fn run(
+    bot_token: impl Into<String>,
+    db_url: impl AsRef<str>,
+    bot_api_url: Option<impl AsRef<str>>,
+) -> anyhow::Result<()> {
+    let mut bot = Bot::new(bot_token);
+    if let Some(bot_api_url) = bot_api_url {
+        let bot_api_url = bot_api_url.as_ref();
+        let url = reqwest::Url::parse(bot_api_url)
+            .map_err(|err| anyhow!("invalid Bot API url '{bot_api_url}'. err: '{err}'"))?;
+
+        info!("using Bot API server '{url}'");
+        bot = bot.set_api_url(url);
+    }
     let inst_state = InstanceState::new(db_url).await?;
+    cache::spawn_pruning(Arc::clone(&inst_state));
 
     bot.set_my_commands(Command::bot_commands()).await?;
 
@@ -61,6 +79,16 @@ pub async fn run(bot_token: impl Into<String>, db_url: impl AsRef<str>) -> anyho
                     let req = handle::Request::edited_message(state, bot, me, msg);
                     _ = handler::handle(req).await;
                 },
+            ))
+            .branch(
+                Update::filter_callback_query().endpoint(|bot: Bot, query: CallbackQuery| {
+                    handler::handle_callback_query(bot, query)
+                }),
+            )
+            .branch(Update::filter_my_chat_member().endpoint(
+                |state: Arc<InstanceState>, update: ChatMemberUpdated| {
+                    handler::handle_my_chat_member(state, update)
+                },
             ));
 
     Dispatcher::builder(bot, handler)
@@ -78,3 +106,8 @@ pub async fn run(bot_token: impl Into<String>, db_url: impl AsRef<str>) -> anyho
 
     Ok(())
 }
+
+// Imports a Telegram Desktop export instead of running the bot
+pub async fn import(db_url: String, options: ImportOptions) -> anyhow::Result<()> {
+    import::run(db_url, options).await
+}
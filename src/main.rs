@@ -16,7 +16,13 @@ async fn main() {
     info!("current version: {}", config::PACKAGE.version);
     info!("logs will be written to '{}'", log_dir.display());
 
-    if let Err(err) = run().await {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.first().map(String::as_str) {
+        Some("import") => import(&args[1..]).await,
+        _ => run().await,
+    };
+
+    if let Err(err) = result {
         error!("exited with err: {err}");
         exit(1);
     }
@@ -68,5 +74,20 @@ async fn run() -> anyhow::Result<()> {
         )
     })?;
 
-    core::run(bot_token, db_url).await
+    let bot_api_url = env::var(config::BOT_API_URL_ENV_VAR).ok();
+
+    core::run(bot_token, db_url, bot_api_url).await
+}
+
+async fn import(args: &[String]) -> anyhow::Result<()> {
+    let options = core::ImportOptions::parse(args)
+        .map_err(|err| anyhow!("{err}\n\n{}", core::IMPORT_USAGE))?;
+    let db_url = env::var(config::DB_URL_ENV_VAR).map_err(|err| {
+        anyhow!(
+            "failed to read database url from env var `{}`. err: '{err}'",
+            config::DB_URL_ENV_VAR
+        )
+    })?;
+
+    core::import(db_url, options).await
 }
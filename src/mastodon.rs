@@ -1,21 +1,36 @@
-use std::{str::FromStr, sync::Arc};
+use std::{collections::HashMap, io::Cursor, str::FromStr, sync::Arc};
 
 use anyhow::{anyhow, bail};
-use const_format::formatcp;
+use futures_util::TryStreamExt;
 use mastodon_async::{
-    entities::attachment::ProcessedAttachment, prelude::*, registration::Registered, scopes,
+    entities::attachment::Attachment, prelude::*, registration::Registered, scopes,
 };
-pub use mastodon_async::{Language, StatusBuilder, Visibility};
+pub use mastodon_async::{
+    entities::attachment::{AttachmentId, ProcessedAttachment},
+    Language, NewStatus, StatusBuilder, Visibility,
+};
+use once_cell::sync::Lazy;
+use reqwest::{
+    multipart::{Form, Part},
+    Body,
+};
+use serde::{Deserialize, Serialize};
 use serde_json as json;
 use spdlog::prelude::*;
 use teloxide::types::UserId;
 use tokio::{
-    fs::File,
-    io::AsyncRead,
-    time::{self},
+    io::{AsyncRead, AsyncReadExt},
+    sync::{Mutex, OwnedSemaphorePermit, Semaphore},
+    task,
+    time::{self, Duration},
 };
+use tokio_util::io::ReaderStream;
 
-use crate::{config, InstanceState};
+use crate::{
+    config,
+    util::{env_duration_or, env_or, media::metadata},
+    InstanceState,
+};
 
 pub struct Client {
     inst_state: Arc<InstanceState>,
@@ -201,6 +216,167 @@ WHERE domain = ?1
     }
 }
 
+static HTTP_CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+static UPLOAD_SEMAPHORE: Lazy<Arc<Semaphore>> = Lazy::new(|| {
+    Arc::new(Semaphore::new(env_or(
+        config::MAX_CONCURRENT_UPLOADS_ENV_VAR,
+        config::DEFAULT_MAX_CONCURRENT_UPLOADS,
+    )))
+});
+
+static MEDIA_PROCESSING_INTERVAL: Lazy<Duration> = Lazy::new(|| {
+    env_duration_or(
+        config::MEDIA_PROCESSING_INTERVAL_ENV_VAR,
+        config::WAITING_FOR_SERVER_PROCESS_MEDIA_INTERVAL,
+    )
+});
+
+static MEDIA_PROCESSING_TIMEOUT: Lazy<Duration> = Lazy::new(|| {
+    env_duration_or(
+        config::MEDIA_PROCESSING_TIMEOUT_ENV_VAR,
+        config::WAITING_FOR_SERVER_PROCESS_MEDIA_TIMEOUT,
+    )
+});
+
+static INSTANCE_UPLOAD_SEMAPHORES: Lazy<Mutex<HashMap<String, Arc<Semaphore>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static INSTANCE_CONFIG_CACHE: Lazy<Mutex<HashMap<String, InstanceConfig>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Only the fields we care about, defaults are taken from the Mastodon source
+// for servers that don't report them.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct InstanceConfig {
+    #[serde(default)]
+    pub statuses: StatusesConfig,
+    #[serde(default)]
+    pub media_attachments: MediaAttachmentsConfig,
+    #[serde(default)]
+    pub polls: PollsConfig,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct StatusesConfig {
+    pub max_characters: usize,
+    pub characters_reserved_per_url: usize,
+}
+
+impl Default for StatusesConfig {
+    fn default() -> Self {
+        Self {
+            max_characters: 500,
+            characters_reserved_per_url: 23,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct MediaAttachmentsConfig {
+    pub supported_mime_types: Vec<String>,
+    pub image_size_limit: u64,
+    pub image_matrix_limit: u64,
+}
+
+impl Default for MediaAttachmentsConfig {
+    fn default() -> Self {
+        Self {
+            supported_mime_types: [
+                "image/jpeg",
+                "image/png",
+                "image/gif",
+                "image/webp",
+                "video/webm",
+                "video/mp4",
+                "video/quicktime",
+                "video/ogg",
+                "audio/wave",
+                "audio/wav",
+                "audio/x-wav",
+                "audio/x-pn-wave",
+                "audio/vnd.wave",
+                "audio/ogg",
+                "audio/vorbis",
+                "audio/mpeg",
+                "audio/mp3",
+                "audio/webm",
+                "audio/flac",
+                "audio/aac",
+                "audio/m4a",
+                "audio/x-m4a",
+                "audio/mp4",
+                "audio/3gpp",
+                "video/x-ms-asf",
+            ]
+            .map(Into::into)
+            .into(),
+            image_size_limit: 16 * 1024 * 1024,
+            image_matrix_limit: 33177600, // 7680x4320
+        }
+    }
+}
+
+impl MediaAttachmentsConfig {
+    pub fn is_supported(&self, mime_type: impl AsRef<str>) -> bool {
+        let mime_type = mime_type.as_ref();
+        self.supported_mime_types.iter().any(|t| t == mime_type)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PollsConfig {
+    pub max_options: usize,
+    pub max_characters_per_option: usize,
+    pub min_expiration: u64,
+    pub max_expiration: u64,
+}
+
+impl Default for PollsConfig {
+    fn default() -> Self {
+        Self {
+            max_options: 4,
+            max_characters_per_option: 50,
+            min_expiration: 300,
+            max_expiration: 2629746,
+        }
+    }
+}
+
+pub struct UploadPermit {
+    _instance: OwnedSemaphorePermit,
+    _global: OwnedSemaphorePermit,
+}
+
+pub enum AttachedMedia {
+    Processed(Box<ProcessedAttachment>),
+    // The server didn't finish processing it in time
+    Processing(AttachmentId),
+}
+
+#[derive(Clone, Debug)]
+pub enum UploadProgress {
+    Sending { sent: u64, total: Option<u64> },
+    // Uploaded, waiting for the server to process it
+    Processing(AttachmentId),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct NewPoll {
+    pub options: Vec<String>,
+    pub expires_in: u64,
+    pub multiple: bool,
+}
+
+pub struct PostedStatus {
+    pub id: String,
+    pub url: String,
+}
+
+#[derive(Clone)]
 pub struct LoginUser {
     inst: Mastodon,
     tg_user_id: UserId,
@@ -215,42 +391,269 @@ impl LoginUser {
         self.tg_user_id
     }
 
+    // Bounds the number of concurrent uploads globally and per instance. The
+    // instance permit is acquired first, so that uploads waiting for a busy
+    // instance don't hold global permits.
+    pub async fn upload_permit(&self) -> UploadPermit {
+        let instance = Arc::clone(
+            INSTANCE_UPLOAD_SEMAPHORES
+                .lock()
+                .await
+                .entry(self.domain().to_owned())
+                .or_insert_with(|| {
+                    Arc::new(Semaphore::new(env_or(
+                        config::MAX_CONCURRENT_UPLOADS_PER_INSTANCE_ENV_VAR,
+                        config::DEFAULT_MAX_CONCURRENT_UPLOADS_PER_INSTANCE,
+                    )))
+                }),
+        );
+
+        // Semaphores are never closed
+        let instance = instance.acquire_owned().await.unwrap();
+        let global = Arc::clone(&UPLOAD_SEMAPHORE).acquire_owned().await.unwrap();
+
+        UploadPermit {
+            _instance: instance,
+            _global: global,
+        }
+    }
+
+    // Streams `data` to the media endpoint as it arrives, `on_progress` is
+    // called with the number of bytes sent so far.
     pub async fn attach_media(
         &self,
-        mut data: impl AsyncRead + Unpin,
+        mut data: impl AsyncRead + Send + Sync + Unpin + 'static,
+        len: Option<u64>,
+        thumbnail: Option<Vec<u8>>,
         description: Option<String>,
+        strip_metadata: bool,
+        on_progress: impl Fn(UploadProgress) + Send + Sync + 'static,
+    ) -> anyhow::Result<AttachedMedia> {
+        let mut header = Vec::with_capacity(metadata::SNIFF_LEN);
+        (&mut data)
+            .take(metadata::SNIFF_LEN as u64)
+            .read_to_end(&mut header)
+            .await?;
+
+        // Images have to be buffered to be stripped, they are small anyway
+        let (data, len): (Box<dyn AsyncRead + Send + Sync + Unpin>, _) =
+            if strip_metadata && metadata::is_supported(&header) {
+                let mut image = header;
+                data.read_to_end(&mut image).await?;
+
+                let image = task::spawn_blocking(|| metadata::strip_or_reencode(image)).await??;
+                let len = image.len() as u64;
+                (Box::new(Cursor::new(image)), Some(len))
+            } else {
+                (Box::new(Cursor::new(header).chain(data)), len)
+            };
+
+        let on_progress = Arc::new(on_progress);
+        let body = Body::wrap_stream(ReaderStream::new(data).inspect_ok({
+            let (on_progress, mut sent) = (Arc::clone(&on_progress), 0);
+            move |chunk| {
+                sent += chunk.len() as u64;
+                on_progress(UploadProgress::Sending { sent, total: len });
+            }
+        }));
+        let part = match len {
+            Some(len) => Part::stream_with_length(body, len),
+            None => Part::stream(body),
+        };
+
+        let mut form = Form::new().part("file", part.file_name("file"));
+        if let Some(thumbnail) = thumbnail {
+            form = form.part("thumbnail", Part::bytes(thumbnail).file_name("thumbnail"));
+        }
+        if let Some(description) = description {
+            form = form.text("description", description);
+        }
+
+        trace!("uploading media");
+        let attachment: Attachment = HTTP_CLIENT
+            .post(self.route("/api/v2/media"))
+            .bearer_auth(&self.inst.data.token)
+            .multipart(form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        on_progress(UploadProgress::Processing(attachment.id.clone()));
+        let id = attachment.id.clone();
+        let attachment = tokio::select! {
+            r = self.inst.wait_for_processing(attachment, (*MEDIA_PROCESSING_INTERVAL).into()) => r?,
+            _ = time::sleep(*MEDIA_PROCESSING_TIMEOUT) => {
+                trace!("upload done, but the server is still processing it");
+                return Ok(AttachedMedia::Processing(id));
+            }
+        };
+
+        trace!("upload done");
+        Ok(AttachedMedia::Processed(Box::new(attachment)))
+    }
+
+    // Keeps waiting for media that was still being processed when
+    // `attach_media` returned
+    pub async fn wait_for_media(
+        &self,
+        id: &AttachmentId,
+        timeout: Duration,
     ) -> anyhow::Result<ProcessedAttachment> {
-        // TODO: Do not write out files when https://github.com/dscottboggs/mastodon-async/issues/60 is implemented
+        let attachment = self.inst.attachment(id).await?;
 
-        let temp_file = tempfile::Builder::new()
-            .prefix(formatcp!(".{}.", config::PACKAGE.name))
-            .tempfile()?;
-        let temp_file = temp_file.path();
+        tokio::select! {
+            r = self.inst.wait_for_processing(attachment, (*MEDIA_PROCESSING_INTERVAL).into()) => Ok(r?),
+            _ = time::sleep(timeout) => bail!("timeout waiting for server processing media")
+        }
+    }
 
-        let mut file = File::create(temp_file).await?;
+    // Only unattached media can be deleted, and it requires Mastodon 4.4+. They
+    // are cleaned up by the server after a while anyway.
+    pub async fn delete_media(&self, id: &AttachmentId) -> anyhow::Result<()> {
+        HTTP_CLIENT
+            .delete(self.route(format!("/api/v1/media/{id}")))
+            .bearer_auth(&self.inst.data.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 
-        trace!("downloading to temp file '{}'", temp_file.display());
-        tokio::io::copy(&mut data, &mut file).await?;
+    pub async fn post_status(
+        &self,
+        status: NewStatus,
+        poll: Option<NewPoll>,
+    ) -> anyhow::Result<PostedStatus> {
+        let Some(poll) = poll else {
+            let posted = self.inst.new_status(status).await?;
+            return Ok(PostedStatus {
+                id: posted.id.as_ref().to_owned(),
+                url: posted.url.unwrap_or_else(|| "*invisible*".to_string()),
+            });
+        };
 
-        trace!("download done, uploading it");
-        let attachment = self.inst.media(temp_file, description).await?;
-        let attachment = tokio::select! {
-            r = self.inst.wait_for_processing(attachment, config::WAITING_FOR_SERVER_PROCESS_MEDIA_INTERVAL.into()) => r,
-            _ = time::sleep(config::WAITING_FOR_SERVER_PROCESS_MEDIA_TIMEOUT) => bail!("timeout waiting for server processing media")
-        }?;
+        // `mastodon-async` doesn't support polls yet, so we send the request
+        // ourselves
+        #[derive(Serialize)]
+        struct NewStatusWithPoll {
+            #[serde(flatten)]
+            status: NewStatus,
+            poll: NewPoll,
+        }
+
+        #[derive(Deserialize)]
+        struct Posted {
+            id: String,
+            url: Option<String>,
+        }
+
+        let posted: Posted = HTTP_CLIENT
+            .post(self.route("/api/v1/statuses"))
+            .bearer_auth(&self.inst.data.token)
+            .json(&NewStatusWithPoll { status, poll })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(PostedStatus {
+            id: posted.id,
+            url: posted.url.unwrap_or_else(|| "*invisible*".to_string()),
+        })
+    }
 
-        trace!("upload done");
-        Ok(attachment)
+    pub async fn instance_config(&self) -> anyhow::Result<InstanceConfig> {
+        #[derive(Deserialize)]
+        struct Instance {
+            #[serde(default)]
+            configuration: InstanceConfig,
+        }
+
+        // Not held during the request, so that a slow instance doesn't block
+        // the others. Concurrent misses of the same domain just fetch it twice.
+        if let Some(config) = INSTANCE_CONFIG_CACHE.lock().await.get(self.domain()) {
+            return Ok(config.clone());
+        }
+
+        let instance: Instance = HTTP_CLIENT
+            .get(self.route("/api/v1/instance"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        trace!(
+            "instance config for domain '{}': {:?}",
+            self.domain(),
+            instance.configuration
+        );
+
+        INSTANCE_CONFIG_CACHE
+            .lock()
+            .await
+            .insert(self.domain().to_owned(), instance.configuration.clone());
+        Ok(instance.configuration)
+    }
+
+    // Whether `user@domain` is the linked account. The domain of handles may
+    // differ from the one serving the web interface, so both are accepted.
+    pub async fn owns_handle(&self, handle: &str) -> anyhow::Result<bool> {
+        let account = self.inst.verify_credentials().await?;
+
+        let host = |url: &str| reqwest::Url::parse(url).ok()?.host_str().map(str::to_owned);
+        Ok([host(self.domain()), host(&account.url)]
+            .into_iter()
+            .flatten()
+            .any(|domain| handle.eq_ignore_ascii_case(&format!("{}@{domain}", account.username))))
+    }
+}
+
+// Whether the public profile of `user@domain` contains the text, in its bio or
+// profile fields. Only servers implementing the Mastodon account lookup API
+// are supported.
+pub async fn profile_contains(handle: &str, text: &str) -> anyhow::Result<bool> {
+    #[derive(Deserialize)]
+    struct Account {
+        note: String,
+        #[serde(default)]
+        fields: Vec<Field>,
     }
 
-    pub async fn post_status(&self, status: NewStatus) -> anyhow::Result<String> {
-        let posted = self.inst.new_status(status.clone()).await?;
-        let url = posted.url.unwrap_or_else(|| "*invisible*".to_string());
-        Ok(url)
+    #[derive(Deserialize)]
+    struct Field {
+        name: String,
+        value: String,
     }
+
+    let (user, domain) = handle
+        .split_once('@')
+        .ok_or_else(|| anyhow!("invalid handle '{handle}'"))?;
+
+    let account: Account = HTTP_CLIENT
+        .get(format!("https://{domain}/api/v1/accounts/lookup"))
+        .query(&[("acct", user)])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(account.note.contains(text)
+        || account
+            .fields
+            .iter()
+            .any(|field| field.name.contains(text) || field.value.contains(text)))
 }
 
 impl LoginUser {
+    fn route(&self, path: impl AsRef<str>) -> String {
+        format!("{}{}", self.inst.data.base, path.as_ref())
+    }
+
     fn serialize(&self) -> String {
         json::to_string(&self.inst.data).unwrap()
     }
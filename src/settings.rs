@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json as json;
+use teloxide::types::UserId;
+
+use crate::InstanceState;
+
+// Defaults of `/post` options, can be overridden for a single post
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserSettings {
+    pub partial: bool,
+    pub partial_note: bool,
+    pub strip_metadata: bool,
+    pub background_processing: bool,
+    pub link: bool,
+    pub quote: bool,
+    pub mention_links: bool,
+    pub keep_tg_links: bool,
+    // Validated when saved, see `util::template`
+    pub template: Option<String>,
+    pub hashtags: HashtagRules,
+    // Applied in order
+    pub text_rules: Vec<TextRule>,
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        Self {
+            partial: false,
+            partial_note: false,
+            strip_metadata: true,
+            background_processing: false,
+            link: false,
+            quote: false,
+            mention_links: false,
+            keep_tg_links: false,
+            template: None,
+            hashtags: HashtagRules::default(),
+            text_rules: vec![],
+        }
+    }
+}
+
+// Applied to hashtags of synced statuses, see `util::hashtag`. Tags are stored
+// without `#`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HashtagRules {
+    pub append: Vec<String>,
+    // Keys are lowercase
+    pub map: BTreeMap<String, String>,
+    pub move_to_end: bool,
+}
+
+// Regex replacement applied to the text of synced messages, see
+// `util::text_rule`. Patterns are validated when added.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextRule {
+    pub pattern: String,
+    // Empty to delete the matches, `$1` and `${name}` refer to capture groups
+    pub replacement: String,
+    // Only applied to messages of the chat if set
+    pub chat_id: Option<i64>,
+}
+
+impl UserSettings {
+    pub async fn load(inst_state: &InstanceState, tg_user_id: UserId) -> anyhow::Result<Self> {
+        let tg_user_id = tg_user_id.0 as i64;
+
+        let record = sqlx::query!(
+            r#"
+SELECT settings_json
+FROM user_settings
+WHERE tg_user_id = ?1
+        "#,
+            tg_user_id,
+        )
+        .fetch_optional(inst_state.db.pool())
+        .await?;
+
+        match record {
+            Some(record) => Ok(json::from_str(&record.settings_json)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub async fn save(&self, inst_state: &InstanceState, tg_user_id: UserId) -> anyhow::Result<()> {
+        let (tg_user_id, settings_json) = (tg_user_id.0 as i64, json::to_string(self)?);
+
+        sqlx::query!(
+            r#"
+INSERT OR REPLACE INTO user_settings ( tg_user_id, settings_json )
+VALUES ( ?1, ?2 )
+        "#,
+            tg_user_id,
+            settings_json
+        )
+        .execute(inst_state.db.pool())
+        .await?;
+
+        Ok(())
+    }
+}
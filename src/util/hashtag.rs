@@ -0,0 +1,206 @@
+use std::ops::Range;
+
+use teloxide::types::MessageEntityKind;
+
+use super::text::MessageText;
+use crate::settings::HashtagRules;
+
+// Makes hashtags of Telegram messages work on the fediverse. `#tag@channel`
+// suffixes are stripped, and spaces are inserted around hashtags glued to other
+// words (common in CJK text), because Mastodon only parses hashtags separated
+// from words.
+
+// Returns the hashtags to be placed at the end of the status, i.e. the moved
+// ones and the default ones that are not in the text yet, with `#`
+pub fn apply(rules: &HashtagRules, msg_text: &mut MessageText) -> Vec<String> {
+    let mut moved = vec![];
+
+    for range in hashtag_ranges(msg_text).into_iter().rev() {
+        let tag = msg_text.slice_utf16(range.clone());
+        let tag = tag.trim_start_matches('#');
+        let tag = tag.split_once('@').map_or(tag, |(tag, _)| tag);
+        let tag = rules
+            .map
+            .get(&tag.to_lowercase())
+            .map_or(tag, String::as_str)
+            .to_owned();
+
+        if rules.move_to_end {
+            moved.push(format!("#{tag}"));
+            remove_with_space(msg_text, range);
+            continue;
+        }
+
+        let replacement = format!("#{tag}");
+        let end = range.start + replacement.encode_utf16().count();
+        msg_text.replace_utf16(range.clone(), &replacement);
+
+        if char_at(msg_text, end).is_some_and(is_word_char) {
+            msg_text.replace_utf16(end..end, " ");
+        }
+        if range.start > 0 && char_before(msg_text, range.start).is_some_and(is_word_char) {
+            msg_text.replace_utf16(range.start..range.start, " ");
+        }
+    }
+    moved.reverse();
+
+    if !moved.is_empty() {
+        let trimmed = msg_text.text().trim_end().encode_utf16().count();
+        let len = msg_text.text().encode_utf16().count();
+        msg_text.replace_utf16(trimmed..len, "");
+    }
+
+    let mut existing = hashtags(msg_text)
+        .into_iter()
+        .map(|tag| tag.to_lowercase())
+        .collect::<Vec<_>>();
+    let appended = rules.append.iter().map(|tag| format!("#{tag}"));
+
+    moved
+        .into_iter()
+        .chain(appended)
+        .filter(|tag| {
+            let lowercase = tag.to_lowercase();
+            let is_new = !existing.contains(&lowercase);
+            existing.push(lowercase);
+            is_new
+        })
+        .collect()
+}
+
+// Appends the hashtags as the last paragraph
+pub fn append(msg_text: &mut MessageText, tags: &[String]) {
+    if tags.is_empty() {
+        return;
+    }
+    if !msg_text.text().trim().is_empty() {
+        msg_text.append_text("\n\n");
+    }
+    for (i, tag) in tags.iter().enumerate() {
+        if i != 0 {
+            msg_text.append_text(" ");
+        }
+        msg_text.append_text_with_entity(tag, MessageEntityKind::Hashtag);
+    }
+}
+
+// With `#`
+pub fn hashtags(msg_text: &MessageText) -> Vec<String> {
+    hashtag_ranges(msg_text)
+        .into_iter()
+        .map(|range| msg_text.slice_utf16(range).to_owned())
+        .collect()
+}
+
+// Accepts a tag with or without `#`, returns it without `#`. Like Mastodon, a
+// tag consists of letters, digits and `_`, and is not only digits.
+pub fn normalize(tag: &str) -> Option<String> {
+    let tag = tag.strip_prefix('#').unwrap_or(tag);
+    let valid = !tag.is_empty()
+        && tag.chars().all(|ch| ch.is_alphanumeric() || ch == '_')
+        && !tag.chars().all(|ch| ch.is_ascii_digit());
+    valid.then(|| tag.to_owned())
+}
+
+fn hashtag_ranges(msg_text: &MessageText) -> Vec<Range<usize>> {
+    let mut ranges = msg_text
+        .entities()
+        .iter()
+        .filter(|entity| matches!(entity.kind, MessageEntityKind::Hashtag))
+        .map(|entity| entity.offset..entity.offset + entity.length)
+        .collect::<Vec<_>>();
+    ranges.sort_by_key(|range| range.start);
+    ranges
+}
+
+// Also removes a space next to it, so that no double spaces are left
+fn remove_with_space(msg_text: &mut MessageText, range: Range<usize>) {
+    let range = if char_at(msg_text, range.end) == Some(' ') {
+        range.start..range.end + 1
+    } else if range.start > 0 && char_before(msg_text, range.start) == Some(' ') {
+        range.start - 1..range.end
+    } else {
+        range
+    };
+    msg_text.replace_utf16(range, "");
+}
+
+fn char_at(msg_text: &MessageText, utf16_index: usize) -> Option<char> {
+    let len = msg_text.text().encode_utf16().count();
+    msg_text
+        .slice_utf16(utf16_index.min(len)..len)
+        .chars()
+        .next()
+}
+
+fn char_before(msg_text: &MessageText, utf16_index: usize) -> Option<char> {
+    msg_text.slice_utf16(0..utf16_index).chars().next_back()
+}
+
+fn is_word_char(ch: char) -> bool {
+    ch.is_alphanumeric() || ch == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::MessageEntity;
+
+    use super::*;
+
+    fn msg_text<'a>(text: &'a str, hashtags: &[&str]) -> MessageText<'a> {
+        let entities = hashtags
+            .iter()
+            .map(|tag| {
+                let start = text.find(tag).unwrap();
+                MessageEntity::new(
+                    MessageEntityKind::Hashtag,
+                    text[..start].encode_utf16().count(),
+                    tag.encode_utf16().count(),
+                )
+            })
+            .collect::<Vec<_>>();
+        MessageText::new(text, entities)
+    }
+
+    #[test]
+    fn apply_rules() {
+        let rules = HashtagRules {
+            append: vec!["news".into(), "Cat".into()],
+            map: [("新闻".into(), "news".into())].into(),
+            move_to_end: false,
+        };
+
+        let mut text = msg_text("今天的#新闻@channel很好 #cat", &["#新闻@channel", "#cat"]);
+        let trailing = apply(&rules, &mut text);
+        assert_eq!(text.text(), "今天的 #news 很好 #cat");
+        assert_eq!(hashtags(&text), ["#news", "#cat"]);
+        assert!(trailing.is_empty());
+
+        let mut text = msg_text("今天的#新闻", &["#新闻"]);
+        assert_eq!(apply(&rules, &mut text), ["#Cat"]);
+        assert_eq!(text.text(), "今天的 #news");
+
+        let rules = HashtagRules {
+            move_to_end: true,
+            ..rules
+        };
+        let mut text = msg_text("#cat meow #新闻 meow\n\n#dog", &["#cat", "#新闻", "#dog"]);
+        let trailing = apply(&rules, &mut text);
+        assert_eq!(text.text(), "meow meow");
+        assert!(hashtags(&text).is_empty());
+        assert_eq!(trailing, ["#cat", "#news", "#dog"]);
+
+        append(&mut text, &trailing);
+        assert_eq!(text.text(), "meow meow\n\n#cat #news #dog");
+        assert_eq!(hashtags(&text), ["#cat", "#news", "#dog"]);
+    }
+
+    #[test]
+    fn normalize_tag() {
+        assert_eq!(normalize("#news").as_deref(), Some("news"));
+        assert_eq!(normalize("新闻_1").as_deref(), Some("新闻_1"));
+        assert_eq!(normalize("#"), None);
+        assert_eq!(normalize("123"), None);
+        assert_eq!(normalize("a-b"), None);
+    }
+}
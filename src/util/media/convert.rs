@@ -0,0 +1,215 @@
+use std::{env, io::Cursor};
+
+use anyhow::bail;
+use const_format::formatcp;
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat,
+    ImageReader,
+};
+use once_cell::sync::Lazy;
+use spdlog::prelude::*;
+use tokio::{fs, process::Command};
+
+use crate::config;
+
+// Re-encodes an image (e.g. a static WebP sticker) into PNG, transparency is
+// preserved.
+pub fn to_png(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory(data)?;
+
+    let mut png = Vec::new();
+    image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+
+    trace!(
+        "converted {}x{} image to PNG, {} -> {} bytes",
+        image.width(),
+        image.height(),
+        data.len(),
+        png.len()
+    );
+    Ok(png)
+}
+
+// Downscales and recompresses an image to fit the instance limits, keeping the
+// aspect ratio. Returns `None` if the image already fits, so that good files
+// are uploaded untouched.
+pub fn fit_image(
+    data: &[u8],
+    size_limit: u64,
+    matrix_limit: u64,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+
+    let (width, height) = decoder.dimensions();
+    let pixels = width as u64 * height as u64;
+    if data.len() as u64 <= size_limit && pixels <= matrix_limit {
+        return Ok(None);
+    }
+
+    // Re-encoding drops EXIF, so the orientation has to be applied to the
+    // pixels
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    if pixels > matrix_limit {
+        image = scale(&image, (matrix_limit as f64 / pixels as f64).sqrt());
+    }
+
+    loop {
+        let encoded = encode_lossy(&image)?;
+        if encoded.len() as u64 <= size_limit {
+            debug!(
+                "fitted {width}x{height} image into {}x{}, {} -> {} bytes",
+                image.width(),
+                image.height(),
+                data.len(),
+                encoded.len()
+            );
+            return Ok(Some(encoded));
+        }
+        if image.width() <= 1 && image.height() <= 1 {
+            bail!("image cannot be compressed under {size_limit} bytes");
+        }
+        image = scale(&image, 0.75);
+    }
+}
+
+// Decodes and encodes the image again, which drops all metadata. Used when the
+// metadata can't be stripped at the container level.
+pub fn reencode(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+
+    let encoded = encode_lossy(&image)?;
+    trace!(
+        "re-encoded {}x{} image, {} -> {} bytes",
+        image.width(),
+        image.height(),
+        data.len(),
+        encoded.len()
+    );
+    Ok(encoded)
+}
+
+fn scale(image: &DynamicImage, factor: f64) -> DynamicImage {
+    let scaled = |n: u32| ((n as f64 * factor) as u32).max(1);
+    image.resize(
+        scaled(image.width()),
+        scaled(image.height()),
+        FilterType::Lanczos3,
+    )
+}
+
+// Images with transparency are kept in PNG, the rest are encoded in JPEG with
+// the configured quality.
+fn encode_lossy(image: &DynamicImage) -> anyhow::Result<Vec<u8>> {
+    static QUALITY: Lazy<u8> = Lazy::new(|| {
+        env::var(config::IMAGE_QUALITY_ENV_VAR)
+            .ok()
+            .and_then(|quality| quality.parse().ok())
+            .filter(|quality| (1..=100).contains(quality))
+            .unwrap_or(config::DEFAULT_IMAGE_QUALITY)
+    });
+
+    let mut encoded = Vec::new();
+    if image.color().has_alpha() {
+        image.write_to(&mut Cursor::new(&mut encoded), ImageFormat::Png)?;
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, *QUALITY))?;
+    }
+    Ok(encoded)
+}
+
+// Converts a video sticker (VP9 WebM with alpha) into GIF using `ffmpeg`, as
+// there is no pure Rust VP9 decoder.
+pub async fn webm_to_gif(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let ffmpeg = env::var(config::FFMPEG_ENV_VAR).unwrap_or_else(|_| "ffmpeg".into());
+
+    let temp_dir = tempfile::Builder::new()
+        .prefix(formatcp!(".{}.", config::PACKAGE.name))
+        .tempdir()?;
+    let (input, output) = (
+        temp_dir.path().join("sticker.webm"),
+        temp_dir.path().join("sticker.gif"),
+    );
+
+    fs::write(&input, data).await?;
+
+    let result = Command::new(&ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-c:v", "libvpx-vp9", "-i"])
+        .arg(&input)
+        .args([
+            "-filter_complex",
+            "[0:v]split[a][b];[a]palettegen=reserve_transparent=1[p];[b][p]paletteuse=alpha_threshold=128",
+            "-f",
+            "gif",
+            "-y",
+        ])
+        .arg(&output)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !result.status.success() {
+        bail!(
+            "'{ffmpeg}' exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+
+    let gif = fs::read(&output).await?;
+    trace!(
+        "converted WebM to GIF, {} -> {} bytes",
+        data.len(),
+        gif.len()
+    );
+    Ok(gif)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn fit() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(400, 200))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        assert!(fit_image(&png, u64::MAX, 400 * 200).unwrap().is_none());
+
+        let fitted = fit_image(&png, u64::MAX, 200 * 100).unwrap().unwrap();
+        let fitted = image::load_from_memory(&fitted).unwrap();
+        assert_eq!((fitted.width(), fitted.height()), (200, 100));
+    }
+
+    #[test]
+    fn webp_to_png() {
+        let mut image = RgbaImage::new(4, 2);
+        image.put_pixel(1, 1, Rgba([255, 0, 0, 128]));
+        let mut webp = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut Cursor::new(&mut webp), ImageFormat::WebP)
+            .unwrap();
+
+        let png = to_png(&webp).unwrap();
+        assert_eq!(image::guess_format(&png).unwrap(), ImageFormat::Png);
+        let converted = image::load_from_memory(&png).unwrap().to_rgba8();
+        assert_eq!(converted.dimensions(), (4, 2));
+        assert_eq!(converted.get_pixel(1, 1), &Rgba([255, 0, 0, 128]));
+        assert_eq!(converted.get_pixel(0, 0), &Rgba([0, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,523 @@
+use anyhow::{anyhow, ensure};
+use image::ImageFormat;
+use spdlog::prelude::*;
+
+use super::convert;
+
+// Metadata is removed at the container level, so the pixel data is never
+// re-encoded. Only the EXIF orientation is written back, otherwise images would
+// be displayed rotated.
+
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+const GPS_IFD_TAG: u16 = 0x8825;
+const DEVICE_TAGS: &[u16] = &[
+    0x010F, // Make
+    0x0110, // Model
+    0x0131, // Software
+    0x013B, // Artist
+    0xA431, // BodySerialNumber, only in the Exif IFD
+];
+const EXIF_IFD_TAG: u16 = 0x8769;
+
+pub struct Stripped {
+    pub data: Vec<u8>,
+    pub removed: Vec<String>,
+}
+
+// Returns `None` if the data is not a supported image or there is nothing to
+// strip.
+pub fn strip(data: &[u8]) -> anyhow::Result<Option<Stripped>> {
+    let (data, removed) = match image::guess_format(data) {
+        Ok(ImageFormat::Jpeg) => strip_jpeg(data)?,
+        Ok(ImageFormat::Png) => strip_png(data)?,
+        Ok(ImageFormat::WebP) => strip_webp(data)?,
+        _ => return Ok(None),
+    };
+
+    Ok((!removed.is_empty()).then_some(Stripped { data, removed }))
+}
+
+// Returns the image to upload in place of `data`. If the container can't be
+// parsed, the image is re-encoded instead, the original is never returned
+// unless there is nothing to strip.
+pub fn strip_or_reencode(data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    match strip(&data) {
+        Ok(Some(stripped)) => {
+            debug!("stripped metadata: {}", stripped.removed.join(", "));
+            Ok(stripped.data)
+        }
+        Ok(None) => Ok(data),
+        Err(err) => {
+            warn!("failed to strip metadata, re-encoding the image: {err}");
+            convert::reencode(&data)
+                .map_err(|err| anyhow!("failed to remove metadata from the image: {err}"))
+        }
+    }
+}
+
+// Enough bytes of the header to tell whether the data is a supported image
+pub const SNIFF_LEN: usize = 16;
+
+pub fn is_supported(header: &[u8]) -> bool {
+    matches!(
+        image::guess_format(header),
+        Ok(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+    )
+}
+
+fn strip_jpeg(data: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<String>)> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut removed = vec![];
+    let mut orientation = None;
+
+    out.extend_from_slice(&data[..2]); // SOI
+    let mut exif_at = out.len();
+    let mut pos = 2;
+
+    loop {
+        ensure!(
+            pos + 4 <= data.len() && data[pos] == 0xFF,
+            "malformed JPEG segment at offset {pos}"
+        );
+        let marker = data[pos + 1];
+        match marker {
+            // Fill byte
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // SOS, the entropy-coded data follows
+            0xDA => break,
+            // Standalone markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        ensure!(
+            len >= 2 && end <= data.len(),
+            "malformed JPEG segment at offset {pos}"
+        );
+        let payload = &data[pos + 4..end];
+
+        match jpeg_segment_label(marker, payload) {
+            Some(label) => {
+                if let Some(tiff) = payload.strip_prefix(EXIF_PREFIX) {
+                    orientation = orientation.or_else(|| exif_orientation(tiff));
+                }
+                removed.push(label);
+            }
+            None => {
+                out.extend_from_slice(&data[pos..end]);
+                // JFIF requires APP0 to be the first segment
+                if marker == 0xE0 && exif_at == 2 {
+                    exif_at = out.len();
+                }
+            }
+        }
+        pos = end;
+    }
+
+    // Everything after the EOI of the primary image (e.g. embedded previews
+    // with their own EXIF) is dropped
+    let eoi = data[pos..]
+        .windows(2)
+        .position(|w| w == [0xFF, 0xD9])
+        .map(|i| pos + i + 2)
+        .unwrap_or(data.len());
+    out.extend_from_slice(&data[pos..eoi]);
+    if eoi < data.len() {
+        removed.push("trailing data".into());
+    }
+
+    if let Some(orientation) = orientation {
+        let mut segment = vec![0xFF, 0xE1];
+        let tiff = orientation_exif(orientation);
+        segment.extend_from_slice(&((2 + EXIF_PREFIX.len() + tiff.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(EXIF_PREFIX);
+        segment.extend_from_slice(&tiff);
+        out.splice(exif_at..exif_at, segment);
+    }
+
+    Ok((out, removed))
+}
+
+fn jpeg_segment_label(marker: u8, payload: &[u8]) -> Option<String> {
+    let label = match marker {
+        // Keep JFIF, but not its extension thumbnails
+        0xE0 if payload.starts_with(b"JFXX\0") => "JFIF thumbnail".into(),
+        0xE1 if payload.starts_with(EXIF_PREFIX) => exif_label(&payload[EXIF_PREFIX.len()..])?,
+        0xE1 if payload.starts_with(b"http://ns.adobe.com/") => "XMP".into(),
+        // ICC profile, required for correct colors
+        0xE2 if payload.starts_with(b"ICC_PROFILE\0") => return None,
+        0xED => "IPTC".into(),
+        // Adobe, affects the color transform
+        0xEE => return None,
+        0xE1..=0xEF => format!("APP{}", marker - 0xE0),
+        0xFE => "comment".into(),
+        _ => return None,
+    };
+    Some(label)
+}
+
+fn strip_png(data: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<String>)> {
+    // Ancillary chunks needed for correct rendering or animation
+    const KEPT_CHUNKS: &[&[u8; 4]] = &[
+        b"tRNS", b"cHRM", b"gAMA", b"iCCP", b"sBIT", b"sRGB", b"cICP", b"mDCv", b"cLLi", b"bKGD",
+        b"hIST", b"pHYs", b"sPLT", b"acTL", b"fcTL", b"fdAT",
+    ];
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut removed = vec![];
+    let mut orientation = None;
+
+    out.extend_from_slice(&data[..8]); // Signature
+    let mut pos = 8;
+
+    while pos < data.len() {
+        ensure!(
+            pos + 12 <= data.len(),
+            "malformed PNG chunk at offset {pos}"
+        );
+        let len = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+        let end = pos + 12 + len;
+        ensure!(end <= data.len(), "malformed PNG chunk at offset {pos}");
+        let ty: &[u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+
+        // Critical chunks are identified by an uppercase first letter
+        if ty[0].is_ascii_uppercase() || KEPT_CHUNKS.contains(&ty) {
+            out.extend_from_slice(&data[pos..end]);
+        } else if ty == b"eXIf" {
+            let tiff = &data[pos + 8..end - 4];
+            match exif_label(tiff) {
+                Some(label) => {
+                    orientation = exif_orientation(tiff);
+                    removed.push(label);
+                }
+                None => out.extend_from_slice(&data[pos..end]),
+            }
+        } else {
+            removed.push(String::from_utf8_lossy(ty).into());
+        }
+
+        pos = end;
+        if ty == b"IEND" {
+            break;
+        }
+    }
+    if pos < data.len() {
+        removed.push("trailing data".into());
+    }
+
+    if let Some(orientation) = orientation {
+        // eXIf must come before IDAT, right after IHDR is always fine
+        let ihdr_end = 8 + 12 + u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+        out.splice(
+            ihdr_end..ihdr_end,
+            png_chunk(b"eXIf", &orientation_exif(orientation)),
+        );
+    }
+
+    Ok((out, removed))
+}
+
+fn png_chunk(ty: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(ty);
+    hasher.update(payload);
+
+    let mut chunk = Vec::with_capacity(12 + payload.len());
+    chunk.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(ty);
+    chunk.extend_from_slice(payload);
+    chunk.extend_from_slice(&hasher.finalize().to_be_bytes());
+    chunk
+}
+
+fn strip_webp(data: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<String>)> {
+    const VP8X_FLAG_EXIF: u8 = 0x08;
+    const VP8X_FLAG_XMP: u8 = 0x04;
+
+    ensure!(
+        data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP",
+        "malformed WebP header"
+    );
+
+    let mut chunks = Vec::with_capacity(data.len());
+    let mut removed = vec![];
+    let mut orientation = None;
+    let mut vp8x_flags_at = None;
+    let mut clear_flags = 0;
+    let mut pos = 12;
+
+    while pos + 8 <= data.len() {
+        let fourcc = &data[pos..pos + 4];
+        let size = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        ensure!(
+            pos + 8 + size <= data.len(),
+            "malformed WebP chunk at offset {pos}"
+        );
+        // Chunks are padded to even sizes
+        let end = (pos + 8 + size + (size & 1)).min(data.len());
+
+        let payload = &data[pos + 8..pos + 8 + size];
+        let exif_label = match fourcc {
+            b"EXIF" => exif_label(payload.strip_prefix(EXIF_PREFIX).unwrap_or(payload)),
+            _ => None,
+        };
+
+        if let Some(label) = exif_label {
+            orientation = exif_orientation(payload.strip_prefix(EXIF_PREFIX).unwrap_or(payload));
+            clear_flags |= VP8X_FLAG_EXIF;
+            removed.push(label);
+        } else if fourcc == b"XMP " {
+            clear_flags |= VP8X_FLAG_XMP;
+            removed.push("XMP".into());
+        } else {
+            if fourcc == b"VP8X" {
+                ensure!(size >= 10, "malformed WebP VP8X chunk at offset {pos}");
+                vp8x_flags_at = Some(chunks.len() + 8);
+            }
+            chunks.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+
+    if let Some(flags_at) = vp8x_flags_at {
+        chunks[flags_at] &= !clear_flags;
+
+        // EXIF is placed after the image data
+        if let Some(orientation) = orientation {
+            let tiff = orientation_exif(orientation);
+            chunks[flags_at] |= VP8X_FLAG_EXIF;
+            chunks.extend_from_slice(b"EXIF");
+            chunks.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+            chunks.extend_from_slice(&tiff);
+        }
+    }
+
+    let mut out = Vec::with_capacity(12 + chunks.len());
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(4 + chunks.len() as u32).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(&chunks);
+
+    Ok((out, removed))
+}
+
+struct Ifd<'a> {
+    tiff: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Ifd<'a> {
+    fn new(tiff: &'a [u8]) -> Option<Self> {
+        let big_endian = match tiff.get(..4)? {
+            b"MM\0*" => true,
+            b"II*\0" => false,
+            _ => return None,
+        };
+        Some(Self { tiff, big_endian })
+    }
+
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let bytes = self.tiff.get(pos..pos + 2)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u16::from_be_bytes(bytes),
+            false => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let bytes = self.tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(match self.big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        })
+    }
+
+    // Returns `(tag, offset of the value field)` of entries in the IFD at `ifd`
+    fn entries(&self, ifd: usize) -> impl Iterator<Item = (u16, usize)> + '_ {
+        let count = self.u16_at(ifd).unwrap_or(0) as usize;
+        (0..count).map_while(move |i| {
+            let entry = ifd + 2 + i * 12;
+            Some((self.u16_at(entry)?, entry + 8))
+        })
+    }
+
+    fn ifd0(&self) -> Option<usize> {
+        Some(self.u32_at(4)? as usize)
+    }
+}
+
+fn exif_orientation(tiff: &[u8]) -> Option<u16> {
+    let ifd = Ifd::new(tiff)?;
+    let (_, value_at) = ifd
+        .entries(ifd.ifd0()?)
+        .find(|(tag, _)| *tag == ORIENTATION_TAG)?;
+
+    // A SHORT is left-aligned in the value field, and 1 means no rotation
+    ifd.u16_at(value_at).filter(|o| (2..=8).contains(o))
+}
+
+// Returns `None` if the EXIF contains nothing but the orientation, which is
+// kept as is.
+fn exif_label(tiff: &[u8]) -> Option<String> {
+    let Some(ifd) = Ifd::new(tiff) else {
+        return Some("EXIF".into());
+    };
+
+    let ifd0 = ifd.ifd0().unwrap_or(0);
+    let mut tags = ifd.entries(ifd0).collect::<Vec<_>>();
+    if matches!(tags[..], [(ORIENTATION_TAG, _)]) && ifd.u32_at(ifd0 + 2 + 12) == Some(0) {
+        return None;
+    }
+
+    if let Some(&(_, value_at)) = tags.iter().find(|(tag, _)| *tag == EXIF_IFD_TAG) {
+        if let Some(exif_ifd) = ifd.u32_at(value_at) {
+            tags.extend(ifd.entries(exif_ifd as usize));
+        }
+    }
+
+    let mut details = vec![];
+    if tags.iter().any(|(tag, _)| *tag == GPS_IFD_TAG) {
+        details.push("GPS");
+    }
+    if tags.iter().any(|(tag, _)| DEVICE_TAGS.contains(tag)) {
+        details.push("device");
+    }
+
+    Some(match details.is_empty() {
+        true => "EXIF".into(),
+        false => format!("EXIF ({})", details.join(", ")),
+    })
+}
+
+// A big-endian TIFF with a single orientation entry in IFD0
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0*".to_vec();
+    tiff.extend_from_slice(&8u32.to_be_bytes()); // Offset of IFD0
+    tiff.extend_from_slice(&1u16.to_be_bytes()); // Entry count
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes()); // SHORT
+    tiff.extend_from_slice(&1u32.to_be_bytes()); // Value count
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0]); // Padding of the value field
+    tiff.extend_from_slice(&0u32.to_be_bytes()); // No next IFD
+    tiff
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+
+    // A little-endian EXIF with orientation 6, Make and a GPS IFD pointer
+    fn camera_exif() -> Vec<u8> {
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        for (tag, ty, value) in [(0x010F, 2, *b"Cat\0"), (0x0112, 3, [6, 0, 0, 0])] {
+            tiff.extend_from_slice(&u16::to_le_bytes(tag));
+            tiff.extend_from_slice(&u16::to_le_bytes(ty));
+            tiff.extend_from_slice(&4u32.to_le_bytes());
+            tiff.extend_from_slice(&value);
+        }
+        tiff.extend_from_slice(&GPS_IFD_TAG.to_le_bytes());
+        tiff.extend_from_slice(&4u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff
+    }
+
+    fn jpeg_with_exif() -> Vec<u8> {
+        let mut jpeg = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 2))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+
+        let tiff = camera_exif();
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&((2 + EXIF_PREFIX.len() + tiff.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(EXIF_PREFIX);
+        segment.extend_from_slice(&tiff);
+        jpeg.splice(2..2, segment);
+        jpeg
+    }
+
+    #[test]
+    fn jpeg() {
+        let jpeg = jpeg_with_exif();
+
+        let stripped = strip(&jpeg).unwrap().unwrap();
+        assert_eq!(stripped.removed, ["EXIF (GPS, device)"]);
+        assert!(!stripped.data.windows(3).any(|w| w == b"Cat"));
+        assert!(image::load_from_memory(&stripped.data).is_ok());
+
+        let exif_at = stripped
+            .data
+            .windows(EXIF_PREFIX.len())
+            .position(|w| w == EXIF_PREFIX)
+            .unwrap();
+        let tiff = &stripped.data[exif_at + EXIF_PREFIX.len()..];
+        assert_eq!(exif_orientation(tiff), Some(6));
+
+        assert!(strip(&stripped.data).unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_jpeg() {
+        let jpeg = jpeg_with_exif();
+        let exif_end = 2 + 4 + EXIF_PREFIX.len() + camera_exif().len();
+
+        // Garbage between segments, decoders skip it
+        let mut garbage = jpeg.clone();
+        garbage.splice(exif_end..exif_end, [0x00, 0x00]);
+        assert!(strip(&garbage).is_err());
+        let uploaded = strip_or_reencode(garbage.clone()).unwrap();
+        assert_ne!(uploaded, garbage);
+        assert!(!uploaded
+            .windows(EXIF_PREFIX.len())
+            .any(|w| w == EXIF_PREFIX));
+        assert!(!uploaded.windows(3).any(|w| w == b"Cat"));
+        assert!(image::load_from_memory(&uploaded).is_ok());
+
+        // Truncated right after the EXIF segment, nothing can be decoded
+        let truncated = jpeg[..exif_end + 2].to_vec();
+        assert!(strip_or_reencode(truncated).is_err());
+    }
+
+    #[test]
+    fn png() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 2))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        assert!(strip(&png).unwrap().is_none());
+
+        png.splice(33..33, png_chunk(b"tEXt", b"Comment\0meow"));
+        let stripped = strip(&png).unwrap().unwrap();
+        assert_eq!(stripped.removed, ["tEXt"]);
+        assert!(image::load_from_memory(&stripped.data).is_ok());
+    }
+
+    #[test]
+    fn malformed_webp() {
+        let mut webp = b"RIFF\x0c\0\0\0WEBPVP8X\0\0\0\0".to_vec();
+        assert!(strip(&webp).is_err());
+
+        webp.splice(16..20, 10u32.to_le_bytes());
+        webp.extend_from_slice(&[0; 10]);
+        assert!(strip(&webp).is_ok());
+    }
+}
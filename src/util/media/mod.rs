@@ -0,0 +1,577 @@
+pub mod convert;
+pub mod metadata;
+
+use std::{env, ops::RangeInclusive, slice, time::Duration};
+
+use anyhow::anyhow;
+use once_cell::sync::Lazy;
+use serde_json as json;
+use spdlog::prelude::*;
+use teloxide::{
+    prelude::*,
+    types::{
+        ChatId, FileMeta, Location,
+        MediaKind::{self as InnerMediaKind, *},
+        MessageEntity, MessageId, MessageKind, PhotoSize,
+    },
+};
+use tokio::time::{self, Instant};
+
+use super::{unix_millis, ProgMsg};
+use crate::{cache, config, InstanceState};
+
+pub struct MediaKind(InnerMediaKind);
+
+impl MediaKind {
+    pub fn inner(&self) -> &InnerMediaKind {
+        &self.0
+    }
+
+    pub fn kind_name(&self) -> &'static str {
+        match &self.0 {
+            Animation(_) => "animation",
+            Audio(_) => "audio",
+            Contact(_) => "contact",
+            Document(_) => "document",
+            Game(_) => "game",
+            Venue(_) => "venue",
+            Location(_) => "location",
+            Photo(_) => "photo",
+            Poll(_) => "poll",
+            Sticker(_) => "sticker",
+            Text(_) => "text",
+            Video(_) => "video",
+            VideoNote(_) => "video note",
+            Voice(_) => "voice message",
+            Migration(_) => "migration",
+        }
+    }
+
+    pub fn file(&self) -> Option<&FileMeta> {
+        let file = match &self.0 {
+            Animation(m) => &m.animation.file,
+            Audio(m) => &m.audio.file,
+            Document(m) => &m.document.file,
+            Photo(m) => &Self::choice_best_photo(&m.photo).file,
+            Sticker(m) => &m.sticker.file,
+            Video(m) => &m.video.file,
+            VideoNote(m) => &m.video_note.file,
+            Voice(m) => &m.voice.file,
+            Contact(_) | Game(_) | Venue(_) | Location(_) | Poll(_) | Text(_) | Migration(_) => {
+                return None
+            }
+        };
+        Some(file)
+    }
+
+    pub fn caption(&self) -> Option<&str> {
+        match &self.0 {
+            Animation(m) => m.caption.as_deref(),
+            Audio(m) => m.caption.as_deref(),
+            Document(m) => m.caption.as_deref(),
+            Game(m) => m.game.text.as_deref(),
+            Photo(m) => m.caption.as_deref(),
+            Text(m) => Some(&*m.text),
+            Video(m) => m.caption.as_deref(),
+            Voice(m) => m.caption.as_deref(),
+            Contact(_) | Venue(_) | Location(_) | Poll(_) | Sticker(_) | VideoNote(_)
+            | Migration(_) => None,
+        }
+    }
+
+    pub fn thumbnail(&self) -> Option<&FileMeta> {
+        let thumb = match &self.0 {
+            Audio(m) => m.audio.thumb.as_ref(),
+            Animation(_) | Contact(_) | Document(_) | Game(_) | Photo(_) | Sticker(_)
+            | Video(_) | VideoNote(_) | Voice(_) | Venue(_) | Location(_) | Poll(_) | Text(_)
+            | Migration(_) => None,
+        };
+        thumb.map(|thumb| &thumb.file)
+    }
+
+    pub fn mime_type(&self) -> Option<&str> {
+        let mime_type = match &self.0 {
+            Animation(m) => m.animation.mime_type.as_ref(),
+            Audio(m) => m.audio.mime_type.as_ref(),
+            Document(m) => {
+                // Telegram clients may report a generic type for files they
+                // don't recognize
+                let mime_type = m
+                    .document
+                    .mime_type
+                    .as_ref()
+                    .filter(|mime_type| mime_type.essence_str() != "application/octet-stream");
+                return mime_type
+                    .map(|mime_type| mime_type.essence_str())
+                    .or_else(|| {
+                        mime_guess::from_path(m.document.file_name.as_deref()?).first_raw()
+                    });
+            }
+            Video(m) => m.video.mime_type.as_ref(),
+            Voice(m) => m.voice.mime_type.as_ref(),
+            Contact(_) | Game(_) | Photo(_) | Sticker(_) | VideoNote(_) | Venue(_)
+            | Location(_) | Poll(_) | Text(_) | Migration(_) => None,
+        };
+        mime_type.map(|mime_type| mime_type.essence_str())
+    }
+
+    pub fn file_name(&self) -> Option<&str> {
+        match &self.0 {
+            Animation(m) => m.animation.file_name.as_deref(),
+            Audio(m) => m.audio.file_name.as_deref(),
+            Document(m) => m.document.file_name.as_deref(),
+            Video(m) => m.video.file_name.as_deref(),
+            Contact(_) | Game(_) | Photo(_) | Sticker(_) | VideoNote(_) | Voice(_) | Venue(_)
+            | Location(_) | Poll(_) | Text(_) | Migration(_) => None,
+        }
+    }
+
+    // Alt text for the attachment
+    pub fn description(&self) -> Option<String> {
+        match &self.0 {
+            Audio(m) => {
+                let name = match (&m.audio.performer, &m.audio.title) {
+                    (Some(performer), Some(title)) => Some(format!("{performer} - {title}")),
+                    (None, Some(name)) | (Some(name), None) => Some(name.clone()),
+                    (None, None) => m.audio.file_name.clone(),
+                }
+                .unwrap_or_else(|| "Audio".into());
+                Some(format!("{name} ({})", format_duration(m.audio.duration)))
+            }
+            Voice(m) => Some(format!(
+                "Voice message ({})",
+                format_duration(m.voice.duration)
+            )),
+            Sticker(m) => m.sticker.emoji.clone(),
+            Animation(_) | Contact(_) | Document(_) | Game(_) | Photo(_) | Video(_)
+            | VideoNote(_) | Venue(_) | Location(_) | Poll(_) | Text(_) | Migration(_) => None,
+        }
+    }
+
+    pub fn location_text(&self) -> Option<String> {
+        let text = match &self.0 {
+            Location(m) => format!(
+                "📍 {}\n{}",
+                if m.location.live_period.is_some() {
+                    "Live location"
+                } else {
+                    "Location"
+                },
+                map_url(&m.location)
+            ),
+            Venue(m) => {
+                let mut text = format!("📍 {}\n", m.venue.title);
+                if !m.venue.address.is_empty() {
+                    text.push_str(&format!("{}\n", m.venue.address));
+                }
+                text.push_str(&map_url(&m.venue.location));
+                text
+            }
+            Animation(_) | Audio(_) | Contact(_) | Document(_) | Game(_) | Photo(_) | Poll(_)
+            | Sticker(_) | Text(_) | Video(_) | VideoNote(_) | Voice(_) | Migration(_) => {
+                return None
+            }
+        };
+        Some(text)
+    }
+
+    pub fn entities(&self) -> Option<&[MessageEntity]> {
+        let caption_entities = match &self.0 {
+            Animation(m) => &m.caption_entities,
+            Audio(m) => &m.caption_entities,
+            Document(m) => &m.caption_entities,
+            Game(m) => return m.game.text_entities.as_deref(),
+            Photo(m) => &m.caption_entities,
+            Text(m) => &m.entities,
+            Video(m) => &m.caption_entities,
+            Voice(m) => &m.caption_entities,
+            Contact(_) | Venue(_) | Location(_) | Poll(_) | Sticker(_) | VideoNote(_)
+            | Migration(_) => return None,
+        };
+        Some(caption_entities)
+    }
+
+    pub fn has_media_spoiler(&self) -> bool {
+        match &self.0 {
+            Animation(m) => m.has_media_spoiler,
+            Photo(m) => m.has_media_spoiler,
+            Video(m) => m.has_media_spoiler,
+            Audio(_) | Contact(_) | Document(_) | Game(_) | Venue(_) | Location(_) | Poll(_)
+            | Sticker(_) | Text(_) | VideoNote(_) | Voice(_) | Migration(_) => false,
+        }
+    }
+
+    pub fn choice_best_photo(photos: &[PhotoSize]) -> &PhotoSize {
+        photos
+            .iter()
+            .max_by(|a, b| (a.width * b.height).cmp(&(b.width * b.height)))
+            .unwrap()
+    }
+}
+
+impl MediaKind {
+    fn serialize(inner: &InnerMediaKind) -> anyhow::Result<String> {
+        Ok(json::to_string(inner)?)
+    }
+
+    fn deserialize(data: impl AsRef<str>) -> anyhow::Result<Self> {
+        Ok(Self(json::from_str(data.as_ref())?))
+    }
+}
+
+fn format_duration(secs: u32) -> String {
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{secs:02}")
+    } else {
+        format!("{minutes}:{secs:02}")
+    }
+}
+
+fn map_url(location: &Location) -> String {
+    static MAP_URL: Lazy<String> = Lazy::new(|| {
+        env::var(config::MAP_URL_ENV_VAR).unwrap_or_else(|_| config::DEFAULT_MAP_URL.into())
+    });
+
+    MAP_URL
+        .replace("{lat}", &format!("{:.6}", location.latitude))
+        .replace("{lon}", &format!("{:.6}", location.longitude))
+}
+
+pub enum Media {
+    Single(Box<MediaKind>),
+    Group {
+        medias: Vec<MediaKind>,
+        msg_ids: Vec<MessageId>,
+        group_id: String,
+    },
+}
+
+impl Media {
+    pub fn iter(&self) -> impl Iterator<Item = &MediaKind> {
+        match self {
+            Self::Single(media) => slice::from_ref(&**media).iter(),
+            Self::Group { medias, .. } => medias.iter(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Single(_) => 1,
+            Self::Group { medias, .. } => medias.len(),
+        }
+    }
+
+    pub fn caption(&self) -> Option<&str> {
+        match self {
+            Self::Single(media) => media.caption(),
+            Self::Group { medias, .. } => medias.iter().find_map(|m| m.caption()),
+        }
+    }
+
+    pub fn msg_id_range(&self, msg: &Message) -> RangeInclusive<MessageId> {
+        match self {
+            Self::Single(_) => msg.id..=msg.id,
+            // sorted by `query_media_group`
+            Self::Group { msg_ids, .. } => {
+                *msg_ids.first().unwrap_or(&msg.id)..=*msg_ids.last().unwrap_or(&msg.id)
+            }
+        }
+    }
+
+    pub fn entities(&self) -> Option<&[MessageEntity]> {
+        match self {
+            Self::Single(media) => media.entities(),
+            Self::Group { medias, .. } => medias
+                .iter()
+                .find(|m| m.caption().is_some())
+                .and_then(|m| m.entities()),
+        }
+    }
+
+    pub async fn query(
+        state: &InstanceState,
+        msg: &Message,
+        prog_msg: &mut ProgMsg<'_>,
+    ) -> anyhow::Result<Option<Self>> {
+        let result = match msg.media_group_id() {
+            None => return Ok(Self::single(msg)),
+            Some(media_group_id) => {
+                wait_for_media_group(state, media_group_id, prog_msg)
+                    .await
+                    .map_err(|err| anyhow!("failed to wait for media group: {err}"))?;
+
+                let (msg_ids, medias) = query_media_group(state, media_group_id)
+                    .await
+                    .map_err(|err| anyhow!("failed to query media group: {err}"))?
+                    .into_iter()
+                    .unzip();
+
+                Self::Group {
+                    medias,
+                    msg_ids,
+                    group_id: media_group_id.into(),
+                }
+            }
+        };
+
+        Ok(Some(result))
+    }
+}
+
+impl Media {
+    fn single(msg: &Message) -> Option<Self> {
+        match &msg.kind {
+            MessageKind::Common(common) if !matches!(common.media_kind, Text(_)) => {
+                Some(Self::Single(Box::new(MediaKind(common.media_kind.clone()))))
+            }
+            _ => None,
+        }
+    }
+
+    // From the messages of a single post, i.e. a message or all messages of an
+    // album
+    pub fn from_messages(msgs: &[Message]) -> Option<Self> {
+        let first = msgs.first()?;
+
+        let Some(media_group_id) = first.media_group_id() else {
+            return Self::single(first);
+        };
+
+        let (msg_ids, medias) = msgs
+            .iter()
+            .filter_map(|msg| match &msg.kind {
+                MessageKind::Common(common) => Some((msg.id, MediaKind(common.media_kind.clone()))),
+                _ => None,
+            })
+            .unzip();
+
+        Some(Self::Group {
+            medias,
+            msg_ids,
+            group_id: media_group_id.into(),
+        })
+    }
+}
+
+pub async fn on_new_or_edited_message(inst_state: &InstanceState, msg: &Message) {
+    let msgc = match &msg.kind {
+        MessageKind::Common(common) => common,
+        _ => return,
+    };
+
+    let is_cacheable =
+        msg.media_group_id().is_some() || matches!(msgc.media_kind, Location(_) | Venue(_));
+    if !is_cacheable {
+        return;
+    }
+
+    match cache::should_cache(inst_state, &msg.chat).await {
+        Ok(true) => {}
+        Ok(false) => {
+            trace!("caching is disabled for chat '{}'", msg.chat.id);
+            return;
+        }
+        Err(err) => {
+            error!(
+                "failed to query cache scope. chat id '{}', err: '{err}'",
+                msg.chat.id
+            );
+            return;
+        }
+    }
+
+    if let Some(media_group_id) = msg.media_group_id() {
+        _ = insert_media_to_group(
+            inst_state,
+            &msgc.media_kind,
+            msg.chat.id,
+            msg.id,
+            media_group_id,
+        )
+        .await
+        .map_err(|err| {
+            error!(
+                "failed to cache media. chat id '{}', msg id '{}', err: '{}'",
+                msg.chat.id, msg.id, err
+            );
+        });
+
+        trace!(
+            "media cached successfully. chat id '{}', msg id '{}'",
+            msg.chat.id,
+            msg.id
+        );
+    } else if let Location(_) | Venue(_) = msgc.media_kind {
+        _ = insert_location(inst_state, &msgc.media_kind, msg.chat.id, msg.id)
+            .await
+            .map_err(|err| {
+                error!(
+                    "failed to cache location. chat id '{}', msg id '{}', err: '{}'",
+                    msg.chat.id, msg.id, err
+                );
+            });
+
+        trace!(
+            "location cached successfully. chat id '{}', msg id '{}'",
+            msg.chat.id,
+            msg.id
+        );
+    }
+}
+
+// Finds a location or venue sent right before or after the given messages
+pub async fn query_adjacent_location(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    msg_ids: RangeInclusive<MessageId>,
+) -> anyhow::Result<Option<MediaKind>> {
+    let chat_id = chat_id.0;
+    let (prev_msg_id, next_msg_id) = (msg_ids.start().0 - 1, msg_ids.end().0 + 1);
+
+    let record = sqlx::query!(
+        r#"
+SELECT media_json
+FROM telegram_location
+WHERE chat_id = ?1 AND msg_id IN ( ?2, ?3 )
+ORDER BY msg_id DESC
+        "#,
+        chat_id,
+        prev_msg_id,
+        next_msg_id
+    )
+    .fetch_optional(inst_state.db.pool())
+    .await?;
+
+    record
+        .map(|r| MediaKind::deserialize(r.media_json))
+        .transpose()
+}
+
+async fn insert_media_to_group(
+    inst_state: &InstanceState,
+    media: &InnerMediaKind,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    media_group_id: impl AsRef<str>,
+) -> anyhow::Result<()> {
+    let media_json = MediaKind::serialize(media)?;
+    let (chat_id, msg_id) = (chat_id.0, msg_id.0);
+    let media_group_id = media_group_id.as_ref();
+    let received_at = unix_millis();
+
+    sqlx::query!(
+        r#"
+INSERT OR REPLACE INTO telegram_media_group ( group_id, msg_id, media_json, received_at, chat_id )
+VALUES ( ?1, ?2, ?3, ?4, ?5 )
+        "#,
+        media_group_id,
+        msg_id,
+        media_json,
+        received_at,
+        chat_id
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(())
+}
+
+async fn query_media_group(
+    inst_state: &InstanceState,
+    media_group_id: impl AsRef<str>,
+) -> anyhow::Result<Vec<(MessageId, MediaKind)>> {
+    let media_group_id = media_group_id.as_ref();
+
+    let records = sqlx::query!(
+        r#"
+SELECT msg_id, media_json
+FROM telegram_media_group
+WHERE group_id = ?1
+ORDER BY msg_id
+        "#,
+        media_group_id,
+    )
+    .fetch_all(inst_state.db.pool())
+    .await?;
+
+    records
+        .into_iter()
+        .map(|r| {
+            Ok((
+                MessageId(r.msg_id as i32),
+                MediaKind::deserialize(r.media_json)?,
+            ))
+        })
+        .collect()
+}
+
+// Telegram delivers album items as separate updates, so the album may still be
+// arriving if `/post` is used right after it was sent.
+async fn wait_for_media_group(
+    inst_state: &InstanceState,
+    media_group_id: &str,
+    prog_msg: &mut ProgMsg<'_>,
+) -> anyhow::Result<()> {
+    let deadline = Instant::now() + config::MEDIA_GROUP_MAX_SETTLE_WAIT;
+    let mut notified = false;
+
+    loop {
+        let record = sqlx::query!(
+            r#"
+SELECT COUNT(*) AS "count!: i64", COALESCE(MAX(received_at), 0) AS "last_received_at!: i64"
+FROM telegram_media_group
+WHERE group_id = ?1
+        "#,
+            media_group_id,
+        )
+        .fetch_one(inst_state.db.pool())
+        .await?;
+
+        if record.count as usize >= config::MEDIA_GROUP_MAX_SIZE {
+            return Ok(());
+        }
+
+        let since_last = unix_millis() - record.last_received_at;
+        let remaining = config::MEDIA_GROUP_SETTLE_PERIOD
+            .saturating_sub(Duration::from_millis(since_last.max(0) as u64));
+        if remaining.is_zero() || Instant::now() >= deadline {
+            return Ok(());
+        }
+
+        if !notified {
+            trace!("waiting for media group '{media_group_id}' to finish arriving");
+            prog_msg
+                .update("Waiting for album to finish arriving...", true)
+                .await;
+            notified = true;
+        }
+        time::sleep_until(deadline.min(Instant::now() + remaining)).await;
+    }
+}
+
+async fn insert_location(
+    inst_state: &InstanceState,
+    media: &InnerMediaKind,
+    chat_id: ChatId,
+    msg_id: MessageId,
+) -> anyhow::Result<()> {
+    let media_json = MediaKind::serialize(media)?;
+    let (chat_id, msg_id) = (chat_id.0, msg_id.0);
+    let received_at = unix_millis();
+
+    sqlx::query!(
+        r#"
+INSERT OR REPLACE INTO telegram_location ( chat_id, msg_id, media_json, received_at )
+VALUES ( ?1, ?2, ?3, ?4 )
+        "#,
+        chat_id,
+        msg_id,
+        media_json,
+        received_at
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use std::{net::IpAddr, ops::Range};
+
+use anyhow::bail;
+use spdlog::prelude::*;
+use teloxide::types::{ChatId, MessageEntityKind, User, UserId};
+
+use super::{
+    text::{self, MessageText},
+    unix_millis,
+};
+use crate::InstanceState;
+
+// Telegram mentions mean nothing on the fediverse, and `@username` may even
+// notify an unrelated local user of the instance. Mentions of users with a
+// known fediverse handle are rewritten to it, the others to a t.me link or a
+// plain name.
+
+// Accepts `@user@domain` or `user@domain`, returns `user@domain`
+pub fn parse_handle(handle: &str) -> anyhow::Result<String> {
+    let handle = handle.strip_prefix('@').unwrap_or(handle);
+    let Some((user, domain)) = handle.split_once('@') else {
+        bail!("'{handle}' is not a fediverse handle, it should look like @user@example.com");
+    };
+
+    let valid_user = !user.is_empty()
+        && user
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || matches!(ch, '_' | '.' | '-'));
+    let valid_domain = domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && domain
+            .chars()
+            .all(|ch| ch.is_alphanumeric() || matches!(ch, '.' | '-'));
+    if !valid_user || !valid_domain {
+        bail!("'{handle}' is not a fediverse handle, it should look like @user@example.com");
+    }
+    if !is_public_domain(domain) {
+        bail!("'{domain}' is not a public domain");
+    }
+
+    Ok(handle.to_owned())
+}
+
+// The bot requests the domain to verify handles, so hosts of local networks
+// are rejected. URL parsers also take e.g. `127.1` and `0x7f.1` as IPv4
+// addresses, so the last label has to start with a letter like real TLDs.
+fn is_public_domain(domain: &str) -> bool {
+    let domain = domain.to_lowercase();
+    let tld = domain.rsplit('.').next().unwrap_or_default();
+
+    domain.parse::<IpAddr>().is_err()
+        && tld.starts_with(|ch: char| ch.is_alphabetic())
+        && ![".local", ".internal", ".localhost", ".lan", ".home.arpa"]
+            .iter()
+            .any(|suffix| domain.ends_with(suffix))
+}
+
+// Accepts `@username` or `username`, returns it lowercase without `@`
+pub fn parse_username(username: &str) -> Option<String> {
+    let username = username.strip_prefix('@').unwrap_or(username);
+    let valid = !username.is_empty()
+        && username
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '_');
+    valid.then(|| username.to_ascii_lowercase())
+}
+
+// Handles start unverified, with a code to add to the profile of the account.
+// `verified` is for handles of the account linked with `/auth`.
+pub async fn register(
+    inst_state: &InstanceState,
+    user: &User,
+    handle: &str,
+    verified: bool,
+) -> anyhow::Result<RegisteredHandle> {
+    let tg_user_id = user.id.0 as i64;
+    let tg_username = user.username.as_ref().map(|u| u.to_ascii_lowercase());
+    let verify_code = (!verified).then(|| verify_code(user.id, handle));
+
+    let mut tx = inst_state.db.pool().begin().await?;
+
+    // The username may have been taken over from another registered user
+    sqlx::query!(
+        r#"
+DELETE FROM fedi_handle
+WHERE chat_id = 0 AND ( tg_user_id = ?1 OR tg_username = ?2 )
+        "#,
+        tg_user_id,
+        tg_username
+    )
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+INSERT INTO fedi_handle ( chat_id, tg_user_id, tg_username, handle, verified, verify_code )
+VALUES ( 0, ?1, ?2, ?3, ?4, ?5 )
+        "#,
+        tg_user_id,
+        tg_username,
+        handle,
+        verified,
+        verify_code
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(RegisteredHandle {
+        handle: handle.to_owned(),
+        verify_code,
+    })
+}
+
+// Not a secret, it only proves that the user can edit the profile
+fn verify_code(tg_user_id: UserId, handle: &str) -> String {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&tg_user_id.0.to_le_bytes());
+    hasher.update(handle.as_bytes());
+    hasher.update(&unix_millis().to_le_bytes());
+    format!("tgms-{:08x}", hasher.finalize())
+}
+
+pub async fn verify(inst_state: &InstanceState, tg_user_id: UserId) -> anyhow::Result<()> {
+    let tg_user_id = tg_user_id.0 as i64;
+
+    sqlx::query!(
+        r#"
+UPDATE fedi_handle
+SET verified = 1, verify_code = NULL
+WHERE chat_id = 0 AND tg_user_id = ?1
+        "#,
+        tg_user_id
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(())
+}
+
+pub async fn unregister(inst_state: &InstanceState, tg_user_id: UserId) -> anyhow::Result<bool> {
+    let tg_user_id = tg_user_id.0 as i64;
+
+    let result = sqlx::query!(
+        r#"
+DELETE FROM fedi_handle
+WHERE chat_id = 0 AND tg_user_id = ?1
+        "#,
+        tg_user_id
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(result.rows_affected() != 0)
+}
+
+pub struct RegisteredHandle {
+    pub handle: String,
+    // `None` once verified
+    pub verify_code: Option<String>,
+}
+
+pub async fn registered(
+    inst_state: &InstanceState,
+    tg_user_id: UserId,
+) -> anyhow::Result<Option<RegisteredHandle>> {
+    let tg_user_id = tg_user_id.0 as i64;
+
+    let record = sqlx::query!(
+        r#"
+SELECT handle, verified, verify_code
+FROM fedi_handle
+WHERE chat_id = 0 AND tg_user_id = ?1
+        "#,
+        tg_user_id
+    )
+    .fetch_optional(inst_state.db.pool())
+    .await?;
+
+    Ok(record.map(|r| RegisteredHandle {
+        handle: r.handle,
+        verify_code: (r.verified == 0).then(|| r.verify_code.unwrap_or_default()),
+    }))
+}
+
+// Mappings added by administrators only apply to their chat
+pub async fn set_for_chat(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    tg_username: &str,
+    handle: Option<&str>,
+) -> anyhow::Result<bool> {
+    let chat_id = chat_id.0;
+
+    let mut tx = inst_state.db.pool().begin().await?;
+
+    let result = sqlx::query!(
+        r#"
+DELETE FROM fedi_handle
+WHERE chat_id = ?1 AND tg_username = ?2
+        "#,
+        chat_id,
+        tg_username
+    )
+    .execute(&mut tx)
+    .await?;
+
+    if let Some(handle) = handle {
+        sqlx::query!(
+            r#"
+INSERT INTO fedi_handle ( chat_id, tg_username, handle, verified )
+VALUES ( ?1, ?2, ?3, 1 )
+        "#,
+            chat_id,
+            tg_username,
+            handle
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(result.rows_affected() != 0)
+}
+
+// (Telegram username, handle)
+pub async fn list_for_chat(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let chat_id = chat_id.0;
+
+    let records = sqlx::query!(
+        r#"
+SELECT tg_username as "tg_username!", handle
+FROM fedi_handle
+WHERE chat_id = ?1 AND tg_username IS NOT NULL
+ORDER BY tg_username
+        "#,
+        chat_id
+    )
+    .fetch_all(inst_state.db.pool())
+    .await?;
+
+    Ok(records
+        .into_iter()
+        .map(|r| (r.tg_username, r.handle))
+        .collect())
+}
+
+// Handles registered by the users themselves take precedence, and user ids
+// over usernames, since usernames can change. Unverified handles are ignored.
+async fn lookup(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    tg_user_id: Option<UserId>,
+    tg_username: Option<&str>,
+) -> anyhow::Result<Option<String>> {
+    let (chat_id, tg_user_id) = (chat_id.0, tg_user_id.map(|id| id.0 as i64));
+    let tg_username = tg_username.map(|u| u.to_ascii_lowercase());
+
+    let record = sqlx::query!(
+        r#"
+SELECT handle as "handle!"
+FROM fedi_handle
+WHERE chat_id IN ( 0, ?1 ) AND ( tg_user_id = ?2 OR tg_username = ?3 ) AND verified
+ORDER BY COALESCE(tg_user_id = ?2, 0) DESC, chat_id = 0 DESC
+LIMIT 1
+        "#,
+        chat_id,
+        tg_user_id,
+        tg_username
+    )
+    .fetch_optional(inst_state.db.pool())
+    .await?;
+
+    Ok(record.map(|r| r.handle))
+}
+
+// Unknown mentions become t.me links if `links`, otherwise plain names
+pub async fn apply(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    msg_text: &mut MessageText<'_>,
+    links: bool,
+) {
+    let mut mentions = msg_text
+        .entities()
+        .iter()
+        .filter_map(|entity| {
+            let range = entity.offset..entity.offset + entity.length;
+            match &entity.kind {
+                MessageEntityKind::Mention => Some((range, None)),
+                MessageEntityKind::TextMention { user } => Some((range, Some(user.clone()))),
+                _ => None,
+            }
+        })
+        .collect::<Vec<(Range<usize>, Option<User>)>>();
+    mentions.sort_by_key(|(range, _)| range.start);
+
+    for (range, user) in mentions.into_iter().rev() {
+        let text = msg_text.slice_utf16(range.clone()).to_owned();
+        let username = match &user {
+            Some(user) => user.username.clone(),
+            None => Some(text.trim_start_matches('@').to_owned()),
+        };
+
+        let handle = lookup(
+            inst_state,
+            chat_id,
+            user.as_ref().map(|user| user.id),
+            username.as_deref(),
+        )
+        .await
+        .unwrap_or_else(|err| {
+            error!("failed to query fediverse handle of '{text}': {err}");
+            None
+        });
+
+        let replacement = match (handle, &user) {
+            (Some(handle), _) => format!("@{handle}"),
+            (None, Some(user)) => match text::user_url(user).filter(|_| links) {
+                Some(url) => format!("{text} ({url})"),
+                None => continue,
+            },
+            (None, None) if links => format!("https://t.me/{}", username.unwrap_or_default()),
+            (None, None) => username.unwrap_or_default(),
+        };
+        msg_text.replace_utf16(range, &replacement);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_handle("@me@example.com").unwrap(), "me@example.com");
+        assert_eq!(
+            parse_handle("me.2@sub.example.com").unwrap(),
+            "me.2@sub.example.com"
+        );
+        assert!(parse_handle("@me").is_err());
+        assert!(parse_handle("@me@localhost").is_err());
+        assert!(parse_handle("@m e@example.com").is_err());
+        assert!(parse_handle("@a@127.0.0.1").is_err());
+        assert!(parse_handle("@a@10.0.0.5").is_err());
+        assert!(parse_handle("@a@127.1").is_err());
+        assert!(parse_handle("@a@0x7f.0x1").is_err());
+        assert!(parse_handle("@a@printer.local").is_err());
+        assert!(parse_handle("@a@db.corp.internal").is_err());
+        assert!(parse_handle("@a@intranet").is_err());
+        assert!(parse_handle("@a@例子.测试").is_ok());
+
+        assert_eq!(parse_username("@Someone_1").as_deref(), Some("someone_1"));
+        assert_eq!(parse_username("@"), None);
+        assert_eq!(parse_username("some one"), None);
+    }
+
+    #[test]
+    fn code() {
+        let code = verify_code(UserId(1), "me@example.com");
+        assert!(code.starts_with("tgms-"));
+        assert_eq!(code.len(), "tgms-".len() + 8);
+    }
+}
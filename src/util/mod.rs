@@ -1,8 +1,64 @@
 pub mod handle;
+pub mod hashtag;
 pub mod media;
+pub mod mention;
 mod msg;
+pub mod msgcache;
 mod progmsg;
+pub mod sync_record;
+pub mod template;
 pub mod text;
+pub mod text_rule;
+
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub use msg::*;
 pub use progmsg::*;
+use spdlog::prelude::*;
+
+use crate::cmd::parse_duration;
+
+pub fn env_or(key: &str, default: usize) -> usize {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+pub fn env_duration_or(key: &str, default: Duration) -> Duration {
+    let Ok(value) = env::var(key) else {
+        return default;
+    };
+
+    parse_duration(&value).unwrap_or_else(|err| {
+        warn!("invalid duration '{value}' in env var `{key}`, fallback to default: {err}");
+        default
+    })
+}
+
+pub fn unix_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{bytes} {}", UNITS[0]),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
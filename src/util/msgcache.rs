@@ -0,0 +1,226 @@
+use std::ops::RangeInclusive;
+
+use serde_json as json;
+use spdlog::prelude::*;
+use teloxide::types::{ChatId, Message, MessageId};
+
+use super::unix_millis;
+use crate::{cache::ChatCache, config, InstanceState};
+
+// Full messages of chats that opted in with `/cache +messages`, since the Bot
+// API can't fetch old messages. Unlike the media group cache, this covers all
+// kinds of messages and keeps the previous versions of edited ones.
+
+pub async fn on_new_or_edited_message(inst_state: &InstanceState, msg: &Message, edited: bool) {
+    match ChatCache::load(inst_state, msg.chat.id).await {
+        Ok(chat_cache) if chat_cache.messages && chat_cache.is_enabled(&msg.chat) => {}
+        Ok(_) => return,
+        Err(err) => {
+            error!(
+                "failed to query cache scope. chat id '{}', err: '{err}'",
+                msg.chat.id
+            );
+            return;
+        }
+    }
+
+    _ = insert_message(inst_state, msg, edited)
+        .await
+        .map_err(|err| {
+            error!(
+                "failed to cache message. chat id '{}', msg id '{}', err: '{}'",
+                msg.chat.id, msg.id, err
+            );
+        });
+
+    trace!(
+        "message cached successfully. chat id '{}', msg id '{}'",
+        msg.chat.id,
+        msg.id
+    );
+}
+
+// The user id, or the chat id for messages sent on behalf of a chat
+pub fn sender_id(msg: &Message) -> Option<i64> {
+    match msg.sender_chat() {
+        Some(chat) => Some(chat.id.0),
+        None => msg.from().map(|user| user.id.0 as i64),
+    }
+}
+
+pub struct CachedMessage {
+    pub msg: Message,
+    // Previous versions, from the oldest
+    pub edits: Vec<Message>,
+}
+
+pub async fn query(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    msg_id: MessageId,
+) -> anyhow::Result<Option<CachedMessage>> {
+    let (chat_id, msg_id) = (chat_id.0, msg_id.0);
+
+    let Some(record) = sqlx::query!(
+        r#"
+SELECT msg_json
+FROM telegram_message
+WHERE chat_id = ?1 AND msg_id = ?2
+        "#,
+        chat_id,
+        msg_id
+    )
+    .fetch_optional(inst_state.db.pool())
+    .await?
+    else {
+        return Ok(None);
+    };
+
+    let edits = sqlx::query!(
+        r#"
+SELECT msg_json
+FROM telegram_message_edit
+WHERE chat_id = ?1 AND msg_id = ?2
+ORDER BY replaced_at, rowid
+        "#,
+        chat_id,
+        msg_id
+    )
+    .fetch_all(inst_state.db.pool())
+    .await?
+    .into_iter()
+    .map(|r| json::from_str(&r.msg_json))
+    .collect::<Result<_, _>>()?;
+
+    Ok(Some(CachedMessage {
+        msg: json::from_str(&record.msg_json)?,
+        edits,
+    }))
+}
+
+// Sorted by message id, at most `limit` messages from the start of the range
+pub async fn query_range(
+    inst_state: &InstanceState,
+    chat_id: ChatId,
+    msg_ids: RangeInclusive<MessageId>,
+    limit: usize,
+) -> anyhow::Result<Vec<Message>> {
+    let (chat_id, first_msg_id, last_msg_id) = (chat_id.0, msg_ids.start().0, msg_ids.end().0);
+    let limit = limit as i64;
+
+    let records = sqlx::query!(
+        r#"
+SELECT msg_json
+FROM telegram_message
+WHERE chat_id = ?1 AND msg_id BETWEEN ?2 AND ?3
+ORDER BY msg_id
+LIMIT ?4
+        "#,
+        chat_id,
+        first_msg_id,
+        last_msg_id,
+        limit
+    )
+    .fetch_all(inst_state.db.pool())
+    .await?;
+
+    records
+        .into_iter()
+        .map(|r| Ok(json::from_str(&r.msg_json)?))
+        .collect()
+}
+
+async fn insert_message(
+    inst_state: &InstanceState,
+    msg: &Message,
+    edited: bool,
+) -> anyhow::Result<()> {
+    let msg_json = json::to_string(msg)?;
+    let (chat_id, msg_id) = (msg.chat.id.0, msg.id.0);
+    let sender_id = sender_id(msg);
+    let reply_to_msg_id = msg.reply_to_message().map(|reply_to| reply_to.id.0);
+    let now = unix_millis();
+
+    let mut tx = inst_state.db.pool().begin().await?;
+
+    if edited {
+        sqlx::query!(
+            r#"
+INSERT INTO telegram_message_edit ( chat_id, msg_id, msg_json, replaced_at )
+SELECT chat_id, msg_id, msg_json, ?3
+FROM telegram_message
+WHERE chat_id = ?1 AND msg_id = ?2
+        "#,
+            chat_id,
+            msg_id,
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let max_edits = config::CACHE_MAX_EDITS_PER_MESSAGE as i64;
+        sqlx::query!(
+            r#"
+DELETE FROM telegram_message_edit
+WHERE rowid IN (
+    SELECT rowid
+    FROM telegram_message_edit
+    WHERE chat_id = ?1 AND msg_id = ?2
+    ORDER BY replaced_at DESC, rowid DESC
+    LIMIT -1 OFFSET ?3
+)
+        "#,
+            chat_id,
+            msg_id,
+            max_edits
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+
+    // Keeps the arrival time of edited messages
+    sqlx::query!(
+        r#"
+INSERT INTO telegram_message ( chat_id, msg_id, sender_id, reply_to_msg_id, msg_json, received_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6 )
+ON CONFLICT ( chat_id, msg_id ) DO UPDATE SET
+    sender_id = excluded.sender_id,
+    reply_to_msg_id = excluded.reply_to_msg_id,
+    msg_json = excluded.msg_json
+        "#,
+        chat_id,
+        msg_id,
+        sender_id,
+        reply_to_msg_id,
+        msg_json,
+        now
+    )
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let msg: Message = json::from_str(
+            r#"{"message_id":2,"from":{"id":1,"is_bot":false,"first_name":"A"},"chat":{"id":-100123,"title":"T","type":"supergroup"},"date":1666666666,"edit_date":1666666777,"reply_to_message":{"message_id":1,"from":{"id":1,"is_bot":false,"first_name":"A"},"chat":{"id":-100123,"title":"T","type":"supergroup"},"date":1666666600,"text":"first"},"forward_from_chat":{"id":-100456,"title":"C","username":"channel","type":"channel"},"forward_from_message_id":7,"forward_date":1666666000,"text":"second bold","entities":[{"type":"bold","offset":7,"length":4}]}"#,
+        )
+        .unwrap();
+
+        let restored: Message = json::from_str(&json::to_string(&msg).unwrap()).unwrap();
+        assert_eq!(restored, msg);
+        assert_eq!(sender_id(&restored), Some(1));
+        assert_eq!(
+            restored.reply_to_message().map(|m| m.id),
+            Some(MessageId(1))
+        );
+        assert_eq!(restored.text(), Some("second bold"));
+    }
+}
@@ -1,9 +1,30 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use once_cell::sync::Lazy;
 use teloxide::{
     prelude::*,
-    types::{Message, MessageId},
+    types::{CallbackQuery, InlineKeyboardButton, InlineKeyboardMarkup, Message, MessageId},
+};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    format_size,
+    handle::{Response, ResponseKind},
 };
+use crate::config;
 
-use super::handle::{Response, ResponseKind};
+const CANCEL_CALLBACK_PREFIX: &str = "cancel:";
+
+// Keyed by the callback data of the "Cancel" button. This is accessed in
+// `Drop`, so it's a sync mutex.
+static CANCEL_TOKENS: Lazy<Mutex<HashMap<String, CancelToken>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct CancelToken {
+    owner: Option<UserId>,
+    token: CancellationToken,
+}
 
 pub struct ProgMsg<'a> {
     bot: &'a Bot,
@@ -14,6 +35,8 @@ pub struct ProgMsg<'a> {
     msg_id: Option<MessageId>,
     last_unsaved: Option<String>,
     is_mapped: bool,
+    cancel_data: Option<String>,
+    last_progress_update: Option<Instant>,
 }
 
 impl<'a> ProgMsg<'a> {
@@ -27,9 +50,42 @@ impl<'a> ProgMsg<'a> {
             msg_id: None,
             last_unsaved: None,
             is_mapped: false,
+            cancel_data: None,
+            last_progress_update: None,
+        }
+    }
+
+    // Shows a "Cancel" button with the following updates, only the user who
+    // triggered the message is allowed to press it.
+    pub fn cancellable(&mut self) -> CancellationToken {
+        let data = format!(
+            "{CANCEL_CALLBACK_PREFIX}{}:{}",
+            self.trigger_msg.chat.id, self.trigger_msg.id
+        );
+        let token = CancellationToken::new();
+
+        CANCEL_TOKENS.lock().unwrap().insert(
+            data.clone(),
+            CancelToken {
+                owner: self.trigger_msg.from().map(|u| u.id),
+                token: token.clone(),
+            },
+        );
+        self.cancel_data = Some(data);
+        token
+    }
+
+    // Removes the "Cancel" button with the next update
+    pub fn finish_cancellable(&mut self) {
+        if let Some(data) = self.cancel_data.take() {
+            CANCEL_TOKENS.lock().unwrap().remove(&data);
         }
     }
 
+    pub fn msg_id(&self) -> Option<MessageId> {
+        self.msg_id
+    }
+
     pub fn set_delete_on_drop(&mut self, enable: bool) {
         self.delete_on_drop = enable;
     }
@@ -45,23 +101,31 @@ impl<'a> ProgMsg<'a> {
         }
 
         let text = self.format(Some(&status));
+        let cancel_button = self.cancel_data.as_ref().map(|data| {
+            InlineKeyboardMarkup::new([[InlineKeyboardButton::callback("Cancel", data)]])
+        });
 
         match &self.msg_id {
             None => {
-                let msg = self
+                let mut req = self
                     .bot
                     .send_message(self.trigger_msg.chat.id, text)
-                    .reply_to_message_id(self.trigger_msg.id)
-                    .await;
-                if let Ok(msg) = msg {
+                    .reply_to_message_id(self.trigger_msg.id);
+                if let Some(cancel_button) = cancel_button {
+                    req = req.reply_markup(cancel_button);
+                }
+                if let Ok(msg) = req.await {
                     self.msg_id = Some(msg.id);
                 }
             }
             Some(msg_id) => {
-                _ = self
+                let mut req = self
                     .bot
-                    .edit_message_text(self.trigger_msg.chat.id, *msg_id, text)
-                    .await;
+                    .edit_message_text(self.trigger_msg.chat.id, *msg_id, text);
+                if let Some(cancel_button) = cancel_button {
+                    req = req.reply_markup(cancel_button);
+                }
+                _ = req.await;
             }
         }
 
@@ -72,8 +136,26 @@ impl<'a> ProgMsg<'a> {
         }
     }
 
+    // Progress updates are frequent, they are dropped if the last one was sent
+    // within `PROGRESS_UPDATE_INTERVAL` to avoid hitting the rate limit of
+    // Telegram. Wait for `next_progress_update()` to not miss one.
+    pub async fn update_progress(&mut self, status: impl Into<String>) {
+        if Instant::now() < self.next_progress_update() {
+            return;
+        }
+        self.update(status, false).await;
+        self.last_progress_update = Some(Instant::now());
+    }
+
+    pub fn next_progress_update(&self) -> Instant {
+        self.last_progress_update
+            .map(|last| last + config::PROGRESS_UPDATE_INTERVAL)
+            .unwrap_or_else(Instant::now)
+    }
+
     pub async fn map(&mut self, resp: Response<'a>) -> Response<'a> {
         self.is_mapped = true;
+        self.finish_cancellable();
 
         let Some(msg_id) = self.msg_id else {
             return resp;
@@ -124,6 +206,8 @@ impl<'a> ProgMsg<'a> {
 
 impl<'a> Drop for ProgMsg<'a> {
     fn drop(&mut self) {
+        self.finish_cancellable();
+
         if let Some(msg_id) = self.msg_id {
             let chat_id = self.trigger_msg.chat.id;
             let bot = self.bot.clone();
@@ -141,3 +225,58 @@ impl<'a> Drop for ProgMsg<'a> {
         }
     }
 }
+
+// Returns the answer to the callback query, or `None` if it's not a cancel
+// request.
+pub fn on_cancel_query(query: &CallbackQuery) -> Option<&'static str> {
+    let data = query.data.as_deref()?;
+    if !data.starts_with(CANCEL_CALLBACK_PREFIX) {
+        return None;
+    }
+
+    let tokens = CANCEL_TOKENS.lock().unwrap();
+    let answer = match tokens.get(data) {
+        None => "It's too late to cancel.",
+        Some(entry) if entry.owner.is_some_and(|owner| owner != query.from.id) => {
+            "Only the sender of the command can cancel it."
+        }
+        Some(entry) => {
+            entry.token.cancel();
+            "Cancelling..."
+        }
+    };
+    Some(answer)
+}
+
+pub fn progress_bar(done: u64, total: Option<u64>) -> String {
+    const WIDTH: usize = 10;
+
+    let Some(total) = total.filter(|total| *total > 0) else {
+        return format_size(done);
+    };
+    let percentage = (done.min(total) * 100 / total) as usize;
+    let filled = percentage * WIDTH / 100;
+
+    format!(
+        "{}{} {percentage}% ({} / {})",
+        "▰".repeat(filled),
+        "▱".repeat(WIDTH - filled),
+        format_size(done),
+        format_size(total)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bar() {
+        assert_eq!(progress_bar(0, Some(2048)), "▱▱▱▱▱▱▱▱▱▱ 0% (0 B / 2.0 KiB)");
+        assert_eq!(
+            progress_bar(1536, Some(2048)),
+            "▰▰▰▰▰▰▰▱▱▱ 75% (1.5 KiB / 2.0 KiB)"
+        );
+        assert_eq!(progress_bar(1536, None), "1.5 KiB");
+    }
+}
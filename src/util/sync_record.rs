@@ -0,0 +1,234 @@
+use std::ops::Range;
+
+use spdlog::prelude::*;
+use teloxide::types::{ChatId, ForwardedFrom, Message, MessageEntityKind, MessageId};
+
+use super::{
+    text::{self, LinkedChat, MessageText},
+    unix_millis,
+};
+use crate::{
+    mastodon::{LoginUser, PostedStatus},
+    InstanceState,
+};
+
+// Channel posts often link to earlier posts. Once a message is synced, links to
+// it in later statuses of the same account are rewritten to its status.
+
+pub struct SyncedStatus {
+    // Not recorded by older versions
+    pub id: Option<String>,
+    pub url: String,
+}
+
+// Records the status of the messages, and of the channel posts they were
+// forwarded from
+pub async fn record<'m>(
+    inst_state: &InstanceState,
+    login_user: &LoginUser,
+    msgs: impl IntoIterator<Item = &'m Message>,
+    posted: &PostedStatus,
+) {
+    if reqwest::Url::parse(&posted.url).is_err() {
+        return;
+    }
+
+    for msg in msgs {
+        let forwarded = msg.forward().and_then(|forward| match &forward.from {
+            ForwardedFrom::Chat(chat) => forward.message_id.map(|id| (chat, MessageId(id))),
+            _ => None,
+        });
+
+        for (chat, msg_id) in [(&msg.chat, msg.id)].into_iter().chain(forwarded) {
+            insert_or_log(
+                inst_state,
+                login_user,
+                chat.id,
+                chat.username(),
+                msg_id,
+                posted,
+            )
+            .await;
+        }
+    }
+}
+
+// Same as `record`, for messages known only by their ids, e.g. of an export
+pub async fn record_ids(
+    inst_state: &InstanceState,
+    login_user: &LoginUser,
+    chat_id: ChatId,
+    chat_username: Option<&str>,
+    msg_ids: impl IntoIterator<Item = MessageId>,
+    posted: &PostedStatus,
+) {
+    if reqwest::Url::parse(&posted.url).is_err() {
+        return;
+    }
+
+    for msg_id in msg_ids {
+        insert_or_log(
+            inst_state,
+            login_user,
+            chat_id,
+            chat_username,
+            msg_id,
+            posted,
+        )
+        .await;
+    }
+}
+
+async fn insert_or_log(
+    inst_state: &InstanceState,
+    login_user: &LoginUser,
+    chat_id: ChatId,
+    chat_username: Option<&str>,
+    msg_id: MessageId,
+    posted: &PostedStatus,
+) {
+    if let Err(err) = insert(
+        inst_state,
+        login_user,
+        chat_id,
+        chat_username,
+        msg_id,
+        posted,
+    )
+    .await
+    {
+        error!("failed to record synced message '{msg_id}' of chat '{chat_id}': {err}");
+    }
+}
+
+async fn insert(
+    inst_state: &InstanceState,
+    login_user: &LoginUser,
+    chat_id: ChatId,
+    chat_username: Option<&str>,
+    msg_id: MessageId,
+    posted: &PostedStatus,
+) -> anyhow::Result<()> {
+    let (tg_user_id, domain) = (login_user.tg_user_id().0 as i64, login_user.domain());
+    let (chat_id, msg_id) = (chat_id.0, msg_id.0);
+    let chat_username = chat_username.map(|u| u.to_ascii_lowercase());
+    let now = unix_millis();
+
+    sqlx::query!(
+        r#"
+INSERT INTO sync_record ( tg_user_id, domain, chat_id, chat_username, msg_id, status_id, status_url, synced_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8 )
+        "#,
+        tg_user_id,
+        domain,
+        chat_id,
+        chat_username,
+        msg_id,
+        posted.id,
+        posted.url,
+        now
+    )
+    .execute(inst_state.db.pool())
+    .await?;
+
+    Ok(())
+}
+
+pub async fn query(
+    inst_state: &InstanceState,
+    login_user: &LoginUser,
+    chat: &LinkedChat,
+    msg_id: MessageId,
+) -> anyhow::Result<Option<SyncedStatus>> {
+    let (chat_id, chat_username) = match chat {
+        LinkedChat::Id(id) => (Some(id.0), None),
+        LinkedChat::Username(username) => (None, Some(username.to_ascii_lowercase())),
+    };
+    let (tg_user_id, domain) = (login_user.tg_user_id().0 as i64, login_user.domain());
+    let msg_id = msg_id.0;
+
+    let record = sqlx::query!(
+        r#"
+SELECT status_id, status_url as "status_url!"
+FROM sync_record
+WHERE tg_user_id = ?1 AND domain = ?2 AND msg_id = ?3 AND ( chat_id = ?4 OR chat_username = ?5 )
+ORDER BY synced_at DESC
+LIMIT 1
+        "#,
+        tg_user_id,
+        domain,
+        msg_id,
+        chat_id,
+        chat_username
+    )
+    .fetch_optional(inst_state.db.pool())
+    .await?;
+
+    Ok(record.map(|r| SyncedStatus {
+        id: r.status_id,
+        url: r.status_url,
+    }))
+}
+
+// Links to synced messages are replaced with links to their statuses, or with
+// `keep_tg_links`, the statuses are linked next to them
+pub async fn rewrite_links(
+    inst_state: &InstanceState,
+    login_user: &LoginUser,
+    msg_text: &mut MessageText<'_>,
+    keep_tg_links: bool,
+) {
+    let mut links = msg_text
+        .entities()
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entity)| {
+            let range = entity.offset..entity.offset + entity.length;
+            match &entity.kind {
+                MessageEntityKind::Url => Some((range, None)),
+                MessageEntityKind::TextLink { url } => Some((range, Some((i, url.to_string())))),
+                _ => None,
+            }
+        })
+        .collect::<Vec<(Range<usize>, Option<(usize, String)>)>>();
+    links.sort_by_key(|(range, _)| range.start);
+
+    for (range, text_link) in links.into_iter().rev() {
+        let url = match &text_link {
+            Some((_, url)) => url.clone(),
+            // Telegram also recognizes links without a scheme
+            None => match msg_text.slice_utf16(range.clone()) {
+                url if url.contains("://") => url.to_owned(),
+                url => format!("https://{url}"),
+            },
+        };
+
+        let Some((chat, msg_id)) = text::parse_message_link(&url) else {
+            continue;
+        };
+        let status_url = match query(inst_state, login_user, &chat, msg_id).await {
+            Ok(Some(synced)) => synced.url,
+            Ok(None) => continue,
+            Err(err) => {
+                error!("failed to query synced status of '{url}': {err}");
+                continue;
+            }
+        };
+
+        match text_link {
+            _ if keep_tg_links => {
+                msg_text.replace_utf16(range.end..range.end, &format!(" ({status_url})"));
+            }
+            // Non-empty replacements never remove entities, so the index is
+            // still valid
+            Some((i, _)) => match status_url.parse() {
+                Ok(status_url) => {
+                    msg_text.entities_mut()[i].kind =
+                        MessageEntityKind::TextLink { url: status_url }
+                }
+                Err(err) => warn!("invalid url of synced status '{status_url}': {err}"),
+            },
+            None => msg_text.replace_utf16(range, &status_url),
+        }
+    }
+}
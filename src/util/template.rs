@@ -0,0 +1,318 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use teloxide::types::MessageEntityKind;
+
+use super::text::MessageText;
+
+// User-defined layout of synced statuses, e.g.
+//
+//   📢 {text}{#hashtags}
+//
+//   {hashtags}{/hashtags}{#source_name}
+//   via {source_name}{/source_name}
+//
+// `{var}` is replaced with the value, `{#var}...{/var}` is only rendered if the
+// value is not empty and `{^var}...{/var}` only if it's empty. Literal braces
+// are written as `{{` and `}}`.
+
+// Names and descriptions, in the order of `Var`
+pub const VARS: &[(&str, &str)] = &[
+    ("text", "text of the message, with formatting"),
+    (
+        "source_name",
+        "name of the source, following the rules of +/-src",
+    ),
+    ("source_username", "username of the source"),
+    (
+        "source_link",
+        "link to the original message or forwarded channel post, if public",
+    ),
+    ("tg_link", "link to the message, if the chat has links"),
+    (
+        "hashtags",
+        "hashtags not in the text, i.e. moved and appended ones",
+    ),
+    ("date", "date of the message, e.g. 2023-01-31"),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Var {
+    Text,
+    SourceName,
+    SourceUsername,
+    SourceLink,
+    TgLink,
+    Hashtags,
+    Date,
+}
+
+impl FromStr for Var {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "text" => Self::Text,
+            "source_name" => Self::SourceName,
+            "source_username" => Self::SourceUsername,
+            "source_link" => Self::SourceLink,
+            "tg_link" => Self::TgLink,
+            "hashtags" => Self::Hashtags,
+            "date" => Self::Date,
+            _ => bail!("unknown placeholder '{{{s}}}'"),
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Node {
+    Literal(String),
+    Var(Var),
+    Section {
+        var: Var,
+        inverted: bool,
+        body: Vec<Node>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Default)]
+pub struct Values<'a> {
+    pub text: MessageText<'a>,
+    pub source_name: Option<String>,
+    pub source_username: Option<String>,
+    pub source_link: Option<reqwest::Url>,
+    pub tg_link: Option<reqwest::Url>,
+    pub hashtags: Vec<String>,
+    pub date: String,
+}
+
+impl Values<'_> {
+    fn is_empty(&self, var: Var) -> bool {
+        match var {
+            Var::Text => self.text.text().trim().is_empty(),
+            Var::SourceName => self.source_name.is_none(),
+            Var::SourceUsername => self.source_username.is_none(),
+            Var::SourceLink => self.source_link.is_none(),
+            Var::TgLink => self.tg_link.is_none(),
+            Var::Hashtags => self.hashtags.is_empty(),
+            Var::Date => self.date.is_empty(),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Sections being parsed, with the nodes before them
+        let mut stack: Vec<(Var, bool, Vec<Node>)> = vec![];
+        let mut nodes = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+
+        while let Some(i) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..i]);
+            rest = &rest[i..];
+
+            if let Some(escaped) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+                literal.push_str(&rest[..1]);
+                rest = escaped;
+                continue;
+            }
+            if rest.starts_with('}') {
+                bail!("unmatched '}}', write '}}}}' for a literal one");
+            }
+
+            let end = rest
+                .find('}')
+                .ok_or_else(|| anyhow!("unclosed '{{', write '{{{{' for a literal one"))?;
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+
+            if !literal.is_empty() {
+                nodes.push(Node::Literal(std::mem::take(&mut literal)));
+            }
+
+            if let Some(name) = tag.strip_prefix('#').or_else(|| tag.strip_prefix('^')) {
+                let var = name.parse()?;
+                stack.push((var, tag.starts_with('^'), std::mem::take(&mut nodes)));
+            } else if let Some(name) = tag.strip_prefix('/') {
+                let var = name.parse()?;
+                match stack.pop() {
+                    Some((open, inverted, outer)) if open == var => {
+                        let body = std::mem::replace(&mut nodes, outer);
+                        nodes.push(Node::Section {
+                            var,
+                            inverted,
+                            body,
+                        });
+                    }
+                    _ => bail!("'{{/{name}}}' doesn't close an open section"),
+                }
+            } else {
+                nodes.push(Node::Var(tag.parse()?));
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(literal));
+        }
+
+        if let Some((var, inverted, _)) = stack.pop() {
+            bail!(
+                "section '{{{}{}}}' is not closed",
+                if inverted { '^' } else { '#' },
+                VARS[var as usize].0
+            );
+        }
+
+        let template = Self { nodes };
+        if !template.uses(Var::Text) {
+            bail!("the template must contain {{text}}");
+        }
+        Ok(template)
+    }
+}
+
+impl Template {
+    pub fn uses(&self, var: Var) -> bool {
+        fn uses(nodes: &[Node], var: Var) -> bool {
+            nodes.iter().any(|node| match node {
+                Node::Literal(_) => false,
+                Node::Var(v) => *v == var,
+                Node::Section { body, .. } => uses(body, var),
+            })
+        }
+        uses(&self.nodes, var)
+    }
+
+    // Text and entities are appended with `MessageText` operations, so the
+    // UTF-16 offsets of the entities of `{text}` stay aligned
+    pub fn render(&self, values: &Values) -> MessageText<'static> {
+        fn render(nodes: &[Node], values: &Values, out: &mut MessageText) {
+            for node in nodes {
+                match node {
+                    Node::Literal(literal) => out.append_text(literal),
+                    Node::Var(var) => render_var(*var, values, out),
+                    Node::Section {
+                        var,
+                        inverted,
+                        body,
+                    } => {
+                        if values.is_empty(*var) == *inverted {
+                            render(body, values, out);
+                        }
+                    }
+                }
+            }
+        }
+
+        fn render_var(var: Var, values: &Values, out: &mut MessageText) {
+            let link = |out: &mut MessageText, url: &Option<reqwest::Url>| {
+                if let Some(url) = url {
+                    out.append_text_with_entity(url.as_str(), MessageEntityKind::Url);
+                }
+            };
+
+            match var {
+                Var::Text => out.append(MessageText::new(
+                    values.text.text().to_owned(),
+                    values.text.entities().to_vec(),
+                )),
+                Var::SourceName => out.append_text(values.source_name.as_deref().unwrap_or("")),
+                Var::SourceUsername => {
+                    out.append_text(values.source_username.as_deref().unwrap_or(""))
+                }
+                Var::SourceLink => link(out, &values.source_link),
+                Var::TgLink => link(out, &values.tg_link),
+                Var::Hashtags => {
+                    for (i, hashtag) in values.hashtags.iter().enumerate() {
+                        if i != 0 {
+                            out.append_text(" ");
+                        }
+                        out.append_text_with_entity(hashtag, MessageEntityKind::Hashtag);
+                    }
+                }
+                Var::Date => out.append_text(&values.date),
+            }
+        }
+
+        let mut out = MessageText::new(String::new(), vec![]);
+        render(&self.nodes, values, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::MessageEntity;
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert!("{text}".parse::<Template>().is_ok());
+        assert!("{{{text}}}".parse::<Template>().is_ok());
+        assert!("{#source_name}{^tg_link}{text}{/tg_link}{/source_name}"
+            .parse::<Template>()
+            .is_ok());
+
+        for invalid in [
+            "no text",
+            "{text",
+            "{text}}",
+            "{text} {unknown}",
+            "{#source_name}{text}",
+            "{#source_name}{text}{/tg_link}",
+            "{text}{/source_name}",
+        ] {
+            assert!(invalid.parse::<Template>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn render() {
+        let template: Template =
+            "🐱 {text}{#hashtags}\n{hashtags}{/hashtags}{#source_name}\nvia {source_name}{#source_username} (@{source_username}){/source_username}{/source_name}{^source_name}\n{{no source}}{/source_name}\n{tg_link}"
+                .parse()
+                .unwrap();
+
+        let values = Values {
+            text: MessageText::new(
+                "喵 bold",
+                vec![MessageEntity::new(MessageEntityKind::Bold, 2, 4)],
+            ),
+            source_name: Some("Channel".into()),
+            hashtags: vec!["#cat".into(), "#meow".into()],
+            tg_link: Some("https://t.me/channel/1".parse().unwrap()),
+            ..Default::default()
+        };
+        let rendered = template.render(&values);
+
+        assert_eq!(
+            rendered.text(),
+            "🐱 喵 bold\n#cat #meow\nvia Channel\nhttps://t.me/channel/1"
+        );
+        // "🐱 " is 3 UTF-16 code units
+        assert_eq!(
+            rendered.entities(),
+            &[
+                MessageEntity::new(MessageEntityKind::Bold, 5, 4),
+                MessageEntity::new(MessageEntityKind::Hashtag, 10, 4),
+                MessageEntity::new(MessageEntityKind::Hashtag, 15, 5),
+                MessageEntity::new(MessageEntityKind::Url, 33, 22),
+            ]
+        );
+
+        let rendered = template.render(&Values {
+            text: "text".into(),
+            ..Default::default()
+        });
+        assert_eq!(rendered.text(), "🐱 text\n{no source}\n");
+    }
+}
@@ -1,4 +1,7 @@
-use std::{borrow::Cow, ops::Add};
+use std::{
+    borrow::Cow,
+    ops::{Add, Range},
+};
 
 use teloxide::{
     payloads::SendMessage,
@@ -38,11 +41,55 @@ pub fn message_public_url(chat: &Chat, msg_id: MessageId) -> Option<reqwest::Url
     }
 }
 
+// The chat of a message link, see `parse_message_link`
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkedChat {
+    Username(String),
+    Id(ChatId),
+}
+
+// Accepts `https://t.me/<username>/<id>`, `https://t.me/c/<chat>/<id>` and the
+// web preview `https://t.me/s/<username>/<id>`, with an optional topic id
+// before the message id
+pub fn parse_message_link(url: &str) -> Option<(LinkedChat, MessageId)> {
+    let url = reqwest::Url::parse(url).ok()?;
+    if !matches!(url.host_str(), Some("t.me" | "telegram.me")) {
+        return None;
+    }
+
+    let segments = url.path_segments()?.collect::<Vec<_>>();
+    let chat = match segments.as_slice() {
+        ["c", id, .., _] => LinkedChat::Id(ChatId(format!("-100{id}").parse().ok()?)),
+        ["s", username, .., _] => LinkedChat::Username(username.to_string()),
+        // Reserved, not usernames
+        ["c" | "s", ..] => return None,
+        [username, .., _] => LinkedChat::Username(username.to_string()),
+        _ => return None,
+    };
+    let msg_id = segments.last()?.parse().ok()?;
+
+    Some((chat, MessageId(msg_id)))
+}
+
+// Same as `parse_message_link`, but returns `None` if the link is not a message
+// of the chat
+pub fn parse_message_url(url: &str, chat: &Chat) -> Option<MessageId> {
+    let (linked_chat, msg_id) = parse_message_link(url)?;
+    let is_same_chat = match linked_chat {
+        LinkedChat::Id(id) => id == chat.id,
+        LinkedChat::Username(username) => chat
+            .username()
+            .is_some_and(|chat_username| chat_username.eq_ignore_ascii_case(&username)),
+    };
+
+    is_same_chat.then_some(msg_id)
+}
+
 pub fn user_url(user: &User) -> Option<reqwest::Url> {
     user.tme_url()
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct MessageText<'a> {
     text: Cow<'a, str>,
     entities: Cow<'a, [MessageEntity]>,
@@ -88,6 +135,10 @@ impl<'a> MessageText<'a> {
         self.disable_preview
     }
 
+    pub fn entities_mut(&mut self) -> &mut Vec<MessageEntity> {
+        self.entities.to_mut()
+    }
+
     pub fn into_entities(self) -> Vec<MessageEntity> {
         self.entities.into()
     }
@@ -177,6 +228,49 @@ impl<'a> MessageText<'a> {
         self.entities = other.entities;
     }
 
+    // Ranges are in UTF-16 code units, like the offsets of entities
+    pub fn slice_utf16(&self, range: Range<usize>) -> &str {
+        let (start, end) = (
+            utf16_to_byte_index(&self.text, range.start),
+            utf16_to_byte_index(&self.text, range.end),
+        );
+        &self.text[start..end]
+    }
+
+    // Entities after the range are shifted, and entities overlapping it are
+    // resized. An insertion (empty range) at the start of an entity is not
+    // included in it.
+    pub fn replace_utf16(&mut self, range: Range<usize>, replacement: &str) {
+        let new_end = range.start + replacement.encode_utf16().count();
+        let map_start = |pos: usize| match pos {
+            _ if pos < range.start => pos,
+            _ if pos >= range.end => pos - range.end + new_end,
+            _ => range.start,
+        };
+        let map_end = |pos: usize| match pos {
+            _ if pos <= range.start => pos,
+            _ if pos >= range.end => pos - range.end + new_end,
+            _ => new_end,
+        };
+
+        let entities = self.entities.to_mut();
+        for entity in entities.iter_mut() {
+            let (start, end) = (
+                map_start(entity.offset),
+                map_end(entity.offset + entity.length),
+            );
+            entity.offset = start;
+            entity.length = end.saturating_sub(start);
+        }
+        entities.retain(|entity| entity.length != 0);
+
+        let (start, end) = (
+            utf16_to_byte_index(&self.text, range.start),
+            utf16_to_byte_index(&self.text, range.end),
+        );
+        self.text.to_mut().replace_range(start..end, replacement);
+    }
+
     pub fn extract_semantics(&self) -> String {
         use MessageEntityKind::*;
 
@@ -209,6 +303,17 @@ impl<'a> MessageText<'a> {
     }
 }
 
+fn utf16_to_byte_index(text: &str, utf16_index: usize) -> usize {
+    let mut utf16_len = 0;
+    for (i, ch) in text.char_indices() {
+        if utf16_len >= utf16_index {
+            return i;
+        }
+        utf16_len += ch.len_utf16();
+    }
+    text.len()
+}
+
 impl<'a> From<&'a str> for MessageText<'a> {
     fn from(value: &'a str) -> Self {
         Self::new(value, vec![])
@@ -300,6 +405,81 @@ impl<'a> MessageTextExecutor<'a> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn replace_utf16() {
+        let mut msg_text = MessageText::new(
+            "🐱 #喵@channel meow",
+            vec![
+                MessageEntity::new(MessageEntityKind::Hashtag, 3, 10),
+                MessageEntity::new(MessageEntityKind::Bold, 14, 4),
+            ],
+        );
+
+        assert_eq!(msg_text.slice_utf16(3..13), "#喵@channel");
+
+        msg_text.replace_utf16(5..13, "");
+        msg_text.replace_utf16(3..3, " ");
+        assert_eq!(msg_text.text(), "🐱  #喵 meow");
+        assert_eq!(
+            msg_text.entities(),
+            &[
+                MessageEntity::new(MessageEntityKind::Hashtag, 4, 2),
+                MessageEntity::new(MessageEntityKind::Bold, 7, 4),
+            ]
+        );
+
+        msg_text.replace_utf16(4..6, "");
+        assert_eq!(msg_text.text(), "🐱   meow");
+        assert_eq!(
+            msg_text.entities(),
+            &[MessageEntity::new(MessageEntityKind::Bold, 5, 4)]
+        );
+    }
+
+    #[test]
+    fn parse_url() {
+        let chat: Chat = serde_json::from_str(
+            r#"{"id":-1001234,"title":"T","username":"channel","type":"channel"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            parse_message_url("https://t.me/channel/42", &chat),
+            Some(MessageId(42))
+        );
+        assert_eq!(
+            parse_message_url("https://t.me/Channel/7/42?single", &chat),
+            Some(MessageId(42))
+        );
+        assert_eq!(
+            parse_message_url("https://t.me/c/1234/42", &chat),
+            Some(MessageId(42))
+        );
+        assert_eq!(parse_message_url("https://t.me/c/5678/42", &chat), None);
+        assert_eq!(parse_message_url("https://t.me/other/42", &chat), None);
+        assert_eq!(
+            parse_message_url("https://example.com/channel/42", &chat),
+            None
+        );
+        assert_eq!(parse_message_url("https://t.me/channel", &chat), None);
+
+        assert_eq!(
+            parse_message_link("https://t.me/other/42"),
+            Some((LinkedChat::Username("other".into()), MessageId(42)))
+        );
+        assert_eq!(
+            parse_message_link("https://t.me/c/5678/42"),
+            Some((LinkedChat::Id(ChatId(-1005678)), MessageId(42)))
+        );
+        assert_eq!(parse_message_link("https://t.me/c/abc/42"), None);
+        assert_eq!(parse_message_link("https://t.me/c/1234"), None);
+        assert_eq!(parse_message_link("https://t.me/s/42"), None);
+        assert_eq!(
+            parse_message_link("https://t.me/s/channel/42"),
+            Some((LinkedChat::Username("channel".into()), MessageId(42)))
+        );
+    }
+
     #[test]
     fn appender_text_link() {
         let text = String::new();
@@ -0,0 +1,144 @@
+use regex::{Regex, RegexBuilder};
+use spdlog::prelude::*;
+use teloxide::types::{ChatId, MessageEntityKind};
+
+use super::text::MessageText;
+use crate::{config, settings::TextRule};
+
+// User-defined regex replacements, e.g. to strip tracking parameters or channel
+// footers. Matches are replaced through `MessageText::replace_utf16`, so that
+// entities stay in place. Links of text links are transformed as well.
+
+pub fn compile(pattern: &str) -> anyhow::Result<Regex> {
+    Ok(RegexBuilder::new(pattern)
+        .size_limit(config::TEXT_RULE_REGEX_SIZE_LIMIT)
+        .build()?)
+}
+
+// Returns whether the text was changed
+pub fn apply(rules: &[TextRule], chat_id: ChatId, msg_text: &mut MessageText) -> bool {
+    let mut changed = false;
+
+    for rule in rules
+        .iter()
+        .filter(|rule| rule.chat_id.is_none_or(|id| id == chat_id.0))
+    {
+        let regex = match compile(&rule.pattern) {
+            Ok(regex) => regex,
+            Err(err) => {
+                warn!("skipped invalid text rule '{}': {err}", rule.pattern);
+                continue;
+            }
+        };
+        changed |= apply_rule(&regex, &rule.replacement, msg_text);
+    }
+
+    changed
+}
+
+fn apply_rule(regex: &Regex, replacement: &str, msg_text: &mut MessageText) -> bool {
+    let text = msg_text.text().to_owned();
+    let replacements = regex
+        .captures_iter(&text)
+        .filter_map(|caps| {
+            let matched = caps.get(0)?;
+            let mut expanded = String::new();
+            caps.expand(replacement, &mut expanded);
+            (expanded != matched.as_str()).then(|| {
+                let start = text[..matched.start()].encode_utf16().count();
+                let end = start + matched.as_str().encode_utf16().count();
+                (start..end, expanded)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut changed = !replacements.is_empty();
+
+    // Backwards, so that the ranges of earlier matches stay valid
+    for (range, expanded) in replacements.into_iter().rev() {
+        msg_text.replace_utf16(range, &expanded);
+    }
+
+    for entity in msg_text.entities_mut() {
+        let MessageEntityKind::TextLink { url } = &mut entity.kind else {
+            continue;
+        };
+        let replaced = regex.replace_all(url.as_str(), replacement);
+        if replaced == url.as_str() {
+            continue;
+        }
+        match replaced.parse() {
+            Ok(new_url) => {
+                *url = new_url;
+                changed = true;
+            }
+            Err(err) => warn!("text rule made an invalid link of '{url}': {err}"),
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::MessageEntity;
+
+    use super::*;
+
+    fn rule(pattern: &str, replacement: &str, chat_id: Option<i64>) -> TextRule {
+        TextRule {
+            pattern: pattern.into(),
+            replacement: replacement.into(),
+            chat_id,
+        }
+    }
+
+    #[test]
+    fn apply_rules() {
+        let mut msg_text = MessageText::new(
+            "🐱 see https://example.com/a?utm_source=tg and this\n\nSubscribe to our channel!",
+            vec![
+                MessageEntity::new(MessageEntityKind::Url, 7, 35),
+                MessageEntity::new(
+                    MessageEntityKind::TextLink {
+                        url: "https://example.com/b?utm_source=tg".parse().unwrap(),
+                    },
+                    47,
+                    4,
+                ),
+                MessageEntity::new(MessageEntityKind::Bold, 53, 25),
+            ],
+        );
+        let rules = [
+            rule(r"[?&]utm_[^&\s]*", "", None),
+            rule(r"\s*Subscribe to our channel!$", "", Some(-1001234)),
+            rule(r"(?P<word>see)", "${word}:", None),
+        ];
+
+        assert!(apply(&rules, ChatId(-1005678), &mut msg_text));
+        assert_eq!(
+            msg_text.text(),
+            "🐱 see: https://example.com/a and this\n\nSubscribe to our channel!"
+        );
+        assert_eq!(msg_text.slice_utf16(8..29), "https://example.com/a");
+        assert_eq!(
+            msg_text.entities(),
+            [
+                MessageEntity::new(MessageEntityKind::Url, 8, 21),
+                MessageEntity::new(
+                    MessageEntityKind::TextLink {
+                        url: "https://example.com/b".parse().unwrap(),
+                    },
+                    34,
+                    4,
+                ),
+                MessageEntity::new(MessageEntityKind::Bold, 40, 25),
+            ]
+        );
+
+        assert!(apply(&rules, ChatId(-1001234), &mut msg_text));
+        assert_eq!(msg_text.text(), "🐱 see:: https://example.com/a and this");
+        assert_eq!(msg_text.entities().len(), 2);
+
+        assert!(!apply(&rules[..1], ChatId(-1001234), &mut msg_text));
+    }
+}